@@ -4,6 +4,9 @@
 %define MB_FLAGS (MB_PAGE_ALIGN | MB_MEMORY_INFO)   ; this is the Multiboot 'flag' field
 %define MB_MAGIC 0x1BADB002                         ; 'magic number' lets bootloader find the header
 
+; Where the kernel is linked, see linker.ld
+KERNEL_BASE equ 0xC0000000
+
 ; Multiboot header
 section .multiboot
 align 4
@@ -16,23 +19,79 @@ align 4
         dd 0 ; height: don't care
         dd 0 ; depth: especially don't care
  
-; Our stack
+; Our stack, the idle task keeps using it
 section .bootstrap_stack
 align 4
+global stack_bottom
+global stack_top
 stack_bottom:
 times 16 * 1024 db 0
 stack_top:
 
-section .text
+; Maps the first 4MB both where we are loaded and where we are linked,
+; until virt::init() sets up the real directory
+section .bss
+align 4096
+boot_directory:
+    resb 4096
+%ifdef PAE
+align 32
+boot_pdpt:
+    resb 32
+%endif
+
+; Runs at the physical address it is loaded at, anything linked in the
+; higher half has to be accessed through its physical address
+section .boot progbits alloc exec nowrite align=16
 global _start
 _start:
+%ifdef PAE
+    ; Two 2MB pages, the same directory serves both the first and last gigabyte
+    mov dword [boot_directory - KERNEL_BASE], 0x83
+    mov dword [boot_directory - KERNEL_BASE + 8], 0x200083
+    mov dword [boot_pdpt - KERNEL_BASE], boot_directory - KERNEL_BASE + 1
+    mov dword [boot_pdpt - KERNEL_BASE + 3 * 8], boot_directory - KERNEL_BASE + 1
+
+    mov ecx, cr4
+    or ecx, 0x20 ; PAE
+    mov cr4, ecx
+
+    mov ecx, boot_pdpt - KERNEL_BASE
+%else
+    ; A single 4MB page in both places
+    mov dword [boot_directory - KERNEL_BASE], 0x83
+    mov dword [boot_directory - KERNEL_BASE + (KERNEL_BASE >> 22) * 4], 0x83
+
+    mov ecx, cr4
+    or ecx, 0x10 ; PSE
+    mov cr4, ecx
+
+    mov ecx, boot_directory - KERNEL_BASE
+%endif
+    mov cr3, ecx
+
+    mov ecx, cr0
+    or ecx, 0x80000000
+    mov cr0, ecx
+
+    ; eax and ebx hold the multiboot magic and information, leave them be
+    lea ecx, [higher_half]
+    jmp ecx
+
+section .text
+higher_half:
     ; Set up our stack
     mov esp, stack_top
 
-    ; Rust functions compare esp against [gs:0x30] as a sort of stack guard thing
-    ; as long as we set [gs:0x30] to dword 0, it should be ok
+    ; Rust functions compare esp against [gs:0x30] as a sort of stack guard thing,
+    ; gdt::init() moves the limit into the kernel's own gs segment
     mov [gs:0x30], dword stack_bottom
 
+    ; Hand the multiboot magic and information structure to the kernel, the
+    ; structure is given by its physical address
+    push ebx
+    push eax
+
     extern kernel_main
     call kernel_main
 
@@ -0,0 +1,101 @@
+#include "syscalls.h"
+
+// Times a cheap syscall, brk(0), through int 0x80 and through the vDSO,
+// which uses sysenter when the CPU has it
+
+#define AT_NULL    0
+#define AT_SYSINFO 32
+
+#define ITERATIONS 10000
+
+// The kernel jumps here with argc at the top of the stack, hand that to start()
+asm(".globl _start\n"
+    "_start:\n"
+    "    push %esp\n"
+    "    call start\n");
+
+static unsigned long long rdtsc() {
+    unsigned low, high;
+    asm volatile("rdtsc" : "=a"(low), "=d"(high));
+    return ((unsigned long long) high << 32) | low;
+}
+
+static unsigned brk_int80() {
+    unsigned value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(9), "b"(0) : "memory");
+    return value;
+}
+
+static unsigned brk_vsyscall(void *entry) {
+    unsigned value;
+    asm volatile("call *%1" : "=a"(value) : "r"(entry), "a"(9), "b"(0) : "memory");
+    return value;
+}
+
+static unsigned find_aux(unsigned *sp, unsigned type) {
+    unsigned argc = sp[0];
+    unsigned *p = sp + 1 + argc + 1;
+    // Skip the environment
+    while (*p) {
+        p++;
+    }
+
+    for (p++; p[0] != AT_NULL; p += 2) {
+        if (p[0] == type) {
+            return p[1];
+        }
+    }
+    return 0;
+}
+
+static void print(const char *s) {
+    unsigned len = 0;
+    while (s[len]) {
+        len++;
+    }
+    write(1, s, len);
+}
+
+static void print_number(unsigned n) {
+    char buffer[11];
+    int i = sizeof(buffer);
+    do {
+        buffer[--i] = '0' + n % 10;
+        n /= 10;
+    } while (n);
+    write(1, buffer + i, sizeof(buffer) - i);
+}
+
+static void report(const char *name, unsigned long long before, unsigned long long after) {
+    print(name);
+    print(": ");
+    // Small enough to divide without 64-bit helpers
+    print_number((unsigned) (after - before) / ITERATIONS);
+    print(" cycles per call\n");
+}
+
+void start(unsigned *sp) {
+    unsigned long long before, after;
+    int i;
+
+    before = rdtsc();
+    for (i = 0; i < ITERATIONS; i++) {
+        brk_int80();
+    }
+    after = rdtsc();
+    report("int 0x80", before, after);
+
+    void *entry = (void *) find_aux(sp, AT_SYSINFO);
+    if (entry) {
+        before = rdtsc();
+        for (i = 0; i < ITERATIONS; i++) {
+            brk_vsyscall(entry);
+        }
+        after = rdtsc();
+        report("vDSO", before, after);
+    } else {
+        print("No AT_SYSINFO\n");
+    }
+
+    exit(0);
+}
@@ -1,9 +1,24 @@
+struct user_desc {
+    unsigned int entry_number;
+    unsigned int base_addr;
+    unsigned int limit;
+    unsigned int flags;
+};
+
+struct timespec {
+    int tv_sec;
+    int tv_nsec;
+};
+
 void exit(int code) {
     asm volatile("int $0x80" :: "a"(1), "b"(code));
 }
 
-void write(int fd, const void *buf, unsigned int len) {
-    asm volatile("int $0x80" :: "a"(2), "b"(fd), "c"(buf), "d"(len));
+// Syscalls return a negative errno on failure
+int write(int fd, const void *buf, unsigned int len) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(2), "b"(fd), "c"(buf), "d"(len) : "memory");
+    return value;
 }
 
 unsigned fork() {
@@ -12,6 +27,125 @@ unsigned fork() {
     return value;
 }
 
+// Sleeps for the given number of milliseconds
 void sleep(int duration) {
     asm volatile("int $0x80" :: "a"(4), "b"(duration));
 }
+
+int nanosleep(const struct timespec *req, struct timespec *rem) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(5), "b"(req), "c"(rem) : "memory");
+    return value;
+}
+
+int waitpid(int pid, int *status, int options) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(6), "b"(pid), "c"(status), "d"(options) : "memory");
+    return value;
+}
+
+int execve(const char *path, char *const argv[], char *const envp[]) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(7), "b"(path), "c"(argv), "d"(envp) : "memory");
+    return value;
+}
+
+int set_thread_area(struct user_desc *desc) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(8), "b"(desc) : "memory");
+    return value;
+}
+
+#define PROT_NONE     0
+#define PROT_READ     1
+#define PROT_WRITE    2
+#define PROT_EXEC     4
+
+#define MAP_PRIVATE   0x02
+#define MAP_FIXED     0x10
+#define MAP_ANONYMOUS 0x20
+
+#define MAP_FAILED    ((void *) -1)
+
+// Returns the new end of the heap, or the old one on failure
+void *brk(void *addr) {
+    void *value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(9), "b"(addr));
+    return value;
+}
+
+void *sbrk(int increment) {
+    char *old = brk(0);
+    if (increment == 0) {
+        return old;
+    }
+
+    char *new = brk(old + increment);
+    return new == old + increment ? old : MAP_FAILED;
+}
+
+// Only anonymous mappings are supported
+void *mmap(void *addr, unsigned int len, int prot, int flags, int fd, int offset) {
+    int value;
+    // ebp can't be named as an operand, the offset is moved there by hand
+    asm volatile("push %%ebp; mov %%eax, %%ebp; mov $10, %%eax; int $0x80; pop %%ebp"
+        : "=a"(value) : "a"(offset), "b"(addr), "c"(len), "d"(prot), "S"(flags), "D"(fd) : "memory");
+    return (unsigned int) value >= -4095u ? MAP_FAILED : (void *) value;
+}
+
+int munmap(void *addr, unsigned int len) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(11), "b"(addr), "c"(len) : "memory");
+    return value;
+}
+
+int mprotect(void *addr, unsigned int len, int prot) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(12), "b"(addr), "c"(len), "d"(prot) : "memory");
+    return value;
+}
+
+int read(int fd, void *buf, unsigned int len) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(13), "b"(fd), "c"(buf), "d"(len) : "memory");
+    return value;
+}
+
+int close(int fd) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(14), "b"(fd));
+    return value;
+}
+
+int dup(int fd) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(15), "b"(fd));
+    return value;
+}
+
+int dup2(int old_fd, int new_fd) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(16), "b"(old_fd), "c"(new_fd));
+    return value;
+}
+
+#define SEEK_SET 0
+#define SEEK_CUR 1
+#define SEEK_END 2
+
+int lseek(int fd, int offset, int whence) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(17), "b"(fd), "c"(offset), "d"(whence));
+    return value;
+}
+
+#define PER_LINUX 0x00
+#define PER_ROST  0xff
+
+// Switches to the Linux syscall numbering for this process until it calls
+// execve, returns the previous personality
+int personality(unsigned int persona) {
+    int value;
+    asm volatile("int $0x80" : "=a"(value) : "a"(136), "b"(persona));
+    return value;
+}
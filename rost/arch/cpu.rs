@@ -0,0 +1,25 @@
+use core::prelude::*;
+
+/// Executes cpuid, returning eax, ebx, ecx and edx.
+pub fn cpuid(leaf: u32) -> (u32, u32, u32, u32) {
+    unsafe {
+        let mut a: u32;
+        let mut b: u32;
+        let mut c: u32;
+        let mut d: u32;
+        asm!("cpuid" : "={eax}"(a), "={ebx}"(b), "={ecx}"(c), "={edx}"(d) : "{eax}"(leaf), "{ecx}"(0));
+        (a, b, c, d)
+    }
+}
+
+#[allow(dead_code)]
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    let mut low: u32;
+    let mut high: u32;
+    asm!("rdmsr" : "={eax}"(low), "={edx}"(high) : "{ecx}"(msr) :: "volatile");
+    (high as u64 << 32) | low as u64
+}
+
+pub unsafe fn write_msr(msr: u32, value: u64) {
+    asm!("wrmsr" :: "{ecx}"(msr), "{eax}"(value as u32), "{edx}"((value >> 32) as u32) :: "volatile");
+}
@@ -2,7 +2,22 @@ use core::mem::{size_of, transmute};
 
 use arch::RING3;
 
-static GDT_SIZE: uint = 6;
+static GDT_SIZE: uint = 9;
+
+/// The descriptor user space points %gs at for thread-local storage.
+pub static TLS_ENTRY: u32 = 6;
+pub static TLS_SELECTOR: u16 = (TLS_ENTRY << 3) as u16 | RING3 as u16;
+
+/// The kernel's %gs, see CpuArea.
+pub static KERNEL_GS: u16 = 0x38;
+
+/// The task the double fault handler runs as, see double_fault_task.
+pub static DOUBLE_FAULT_SELECTOR: u16 = 0x40;
+
+// The double fault handler gets a stack of its own, whatever was running
+// when it happened may well have run out
+static DOUBLE_FAULT_STACK_SIZE: uint = 4096;
+
 type GdtTable = [GdtEntry, ..GDT_SIZE];
 
 static GRANULARITY: u8 = 0xc0; // 4kb blocks and 32-bit protected
@@ -36,13 +51,37 @@ struct GdtPtr {
     base: *const GdtTable
 }
 
+// Rust functions compare esp against [gs:0x30] as a stack guard. Instead
+// of relying on something being mapped at address 0x30 the kernel's gs
+// segment starts here.
+#[allow(dead_code)]
+#[packed]
+struct CpuArea {
+    reserved: [u32, ..12],
+    stack_limit: u32
+}
+
 #[allow(dead_code)]
 #[packed]
 struct TssEntry {
     prev_tss: u32,
     esp0: u32,
     ss0: u32,
-    unused: [u32, ..15],
+    esp1: u32,
+    ss1: u32,
+    esp2: u32,
+    ss2: u32,
+    cr3: u32,
+    eip: u32,
+    eflags: u32,
+    eax: u32,
+    ecx: u32,
+    edx: u32,
+    ebx: u32,
+    esp: u32,
+    ebp: u32,
+    esi: u32,
+    edi: u32,
     es: u32,
     cs: u32,
     ss: u32,
@@ -103,13 +142,32 @@ static mut entries: GdtTable = [
     }, ..GDT_SIZE
 ];
 
+static mut cpu_area: CpuArea = CpuArea {
+    reserved: [0, ..12],
+    stack_limit: 0
+};
+
 static mut table: GdtPtr = GdtPtr { limit: 0, base: 0 as *const GdtTable };
 
-static mut tss: TssEntry = TssEntry {
+static EMPTY_TSS: TssEntry = TssEntry {
     prev_tss: 0,
     esp0: 0,
     ss0: 0,
-    unused: [0, ..15],
+    esp1: 0,
+    ss1: 0,
+    esp2: 0,
+    ss2: 0,
+    cr3: 0,
+    eip: 0,
+    eflags: 0,
+    eax: 0,
+    ecx: 0,
+    edx: 0,
+    ebx: 0,
+    esp: 0,
+    ebp: 0,
+    esi: 0,
+    edi: 0,
     es: 0,
     cs: 0,
     ss: 0,
@@ -121,6 +179,10 @@ static mut tss: TssEntry = TssEntry {
     iomap_base: 0
 };
 
+static mut tss: TssEntry = EMPTY_TSS;
+static mut double_fault_tss: TssEntry = EMPTY_TSS;
+static mut double_fault_stack: [u8, ..DOUBLE_FAULT_STACK_SIZE] = [0, ..DOUBLE_FAULT_STACK_SIZE];
+
 pub fn init() {
     unsafe {
         entries[0] = GdtEntry::null();
@@ -129,6 +191,12 @@ pub fn init() {
         entries[3] = GdtEntry::flat(USER | CODE, GRANULARITY);
         entries[4] = GdtEntry::flat(USER | DATA, GRANULARITY);
         entries[5] = write_tss(0x10, 0x0);
+        entries[6] = GdtEntry::flat(USER | DATA, GRANULARITY);
+
+        // Take over the limit boot.asm set up before switching segments
+        cpu_area.stack_limit = read_stack_limit();
+        entries[7] = GdtEntry::new(transmute(&cpu_area), 0xFFFFFFFF, DATA, GRANULARITY);
+        entries[8] = write_double_fault_tss();
 
         table = GdtPtr::new(&entries);
 
@@ -143,6 +211,19 @@ pub fn set_kernel_stack(stack_top: u32) {
     }
 }
 
+/// Returns where the top of the current kernel stack is kept, sysenter
+/// doesn't look at the TSS so sysenter_entry loads esp through this.
+pub fn kernel_stack_pointer() -> u32 {
+    unsafe { transmute(&tss.esp0) }
+}
+
+/// Moves the TLS segment, takes effect the next time %gs is loaded.
+pub fn set_tls(base: u32) {
+    unsafe {
+        entries[TLS_ENTRY as uint] = GdtEntry::new(base as uint, 0xFFFFFFFF, USER | DATA, GRANULARITY);
+    }
+}
+
 unsafe fn write_tss(ss0: u32, esp0: u32) -> GdtEntry {
     tss.ss0 = ss0;
     tss.esp0 = esp0;
@@ -152,12 +233,55 @@ unsafe fn write_tss(ss0: u32, esp0: u32) -> GdtEntry {
     tss.as_gdt_entry()
 }
 
-pub fn set_segments(dataseg: u16) {
+/// Returns eip and esp of whatever was interrupted by the double fault,
+/// only meaningful from within the double fault handler.
+pub fn interrupted_state() -> (u32, u32) {
+    unsafe { (tss.eip, tss.esp) }
+}
+
+// A task switch saves the interrupted state in tss and loads this one. The
+// boot directory maps the kernel image and is never freed, that's enough
+// for reporting the fault.
+unsafe fn write_double_fault_tss() -> GdtEntry {
+    extern { fn double_fault_task(); }
+
+    let stack_bottom: u32 = transmute(&double_fault_stack);
+
+    double_fault_tss.cr3 = read_cr3();
+    double_fault_tss.eip = transmute(double_fault_task);
+    double_fault_tss.eflags = 0x2; // Interrupts disabled
+    double_fault_tss.esp = stack_bottom + DOUBLE_FAULT_STACK_SIZE as u32;
+    double_fault_tss.cs = 0x08;
+    double_fault_tss.ss = 0x10;
+    double_fault_tss.ds = 0x10;
+    double_fault_tss.es = 0x10;
+    double_fault_tss.fs = 0x10;
+    double_fault_tss.gs = KERNEL_GS as u32;
+    double_fault_tss.iomap_base = size_of::<TssEntry>() as u16;
+
+    double_fault_tss.as_gdt_entry()
+}
+
+fn read_cr3() -> u32 {
     unsafe {
-        asm!("mov %ax, %ds;
-              mov %ax, %es;
-              mov %ax, %fs;
-              mov %ax, %gs;" :: "{ax}"(dataseg) :: "volatile");
+        let mut value;
+        asm!("mov %cr3, $0" : "=r"(value));
+        value
+    }
+}
+
+/// Sets the lowest address the current stack may grow down to.
+pub fn set_stack_limit(limit: u32) {
+    unsafe {
+        cpu_area.stack_limit = limit;
+    }
+}
+
+fn read_stack_limit() -> u32 {
+    unsafe {
+        let mut limit;
+        asm!("mov %gs:0x30, $0" : "=r"(limit));
+        limit
     }
 }
 
@@ -166,8 +290,8 @@ fn set_all_segments(dataseg: u16) {
         asm!("mov %ax, %ds;
               mov %ax, %es;
               mov %ax, %fs;
-              mov %ax, %gs;
-              mov %ax, %ss;" :: "{ax}"(dataseg) :: "volatile");
+              mov %ax, %ss;
+              mov %bx, %gs;" :: "{ax}"(dataseg), "{bx}"(KERNEL_GS) :: "volatile");
     }
 }
 
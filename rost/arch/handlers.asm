@@ -10,8 +10,13 @@ common_trap_handler:
     mov ds, ax
     mov es, ax
     mov fs, ax
+    mov ax, 0x38 ; gdt::KERNEL_GS
     mov gs, ax
 
+    ; Interrupt gates leave the direction flag as user mode had it, string
+    ; instructions like those in user_copy need it clear
+    cld
+
     push esp
 
     extern trap_handler
@@ -31,6 +36,143 @@ ret_from_trap:
     add esp, 8 ; trap no and err
     iret
 
+; Entered from __kernel_vsyscall in the vDSO, see exec::vdso. Builds the
+; same frame int 0x80 does so syscalls, fork and preemption can't tell the
+; difference, then returns with sysexit.
+global sysenter_entry
+sysenter_entry:
+    ; The SYSENTER_ESP MSR points at sysenter_stack_top, which holds the
+    ; address of the kernel stack field of the TSS
+    mov esp, [esp]
+    mov esp, [esp]
+
+    push dword 0x23 ; ss
+    push ebp ; esp
+    pushfd
+.clean_flags:
+    ; sysenter leaves TF, DF and NT as user mode had them
+    push dword 0x2
+    popfd
+    ; sysenter clears IF, user mode always runs with interrupts on
+    or dword [esp], 0x200
+    push dword 0x1b ; cs
+    extern sysenter_return
+    push dword [sysenter_return] ; eip
+    push dword 0 ; err
+    push dword 0x80
+
+    ; The sixth argument is on top of the user stack
+    cmp ebp, 0xC0000000 - 4 ; memory::KERNEL_SPACE
+    ja .bad_stack
+.load_arg:
+    mov ebp, [ebp]
+
+    push ds
+    push es
+    push fs
+    push gs
+
+    pusha
+
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov ax, 0x38 ; gdt::KERNEL_GS
+    mov gs, ax
+
+    push esp
+
+    extern trap_handler
+    call trap_handler
+    add esp, 4
+
+    popa
+
+    pop gs
+    pop fs
+    pop es
+    pop ds
+
+    add esp, 8 ; trap no and err
+
+.exit:
+    ; A task being single stepped goes back through iret, which restores
+    ; TF after the return rather than on the way out of the kernel
+    test dword [esp + 8], 0x100
+    jnz .iret
+
+    ; sysexit continues at edx with the stack in ecx, both are restored by
+    ; __kernel_vsyscall
+    mov edx, [esp]
+    mov ecx, [esp + 12]
+
+    ; Interrupts stay off until sysexit has completed, sti only takes
+    ; effect after the next instruction
+    and dword [esp + 8], ~0x200
+    add esp, 8
+    popfd
+    sti
+    sysexit
+
+.iret:
+    iret
+
+    ; Don't run the syscall with a made up sixth argument
+.bad_stack:
+    mov eax, -14 ; EFAULT
+    add esp, 8 ; trap no and err
+    jmp .exit
+
+; The debug trap of a user single stepping into sysenter continues here,
+; see _trap_handler_1. Like sysenter_entry but keeping TF for the return.
+sysenter_entry_traced:
+    mov esp, [esp]
+    mov esp, [esp]
+
+    push dword 0x23 ; ss
+    push ebp ; esp
+    pushfd
+    or dword [esp], 0x100
+    jmp sysenter_entry.clean_flags
+
+section __ex_table progbits alloc noexec nowrite align=4
+    dd sysenter_entry.load_arg, sysenter_entry.bad_stack
+section .text
+
+; sysenter doesn't clear TF, so single stepping user code into it traps on
+; the first instruction of sysenter_entry. The trap is taken on this stack
+; rather than anything that matters, then the entry is restarted with TF
+; cleared.
+section .bss
+global sysenter_stack_top
+    resd 16
+sysenter_stack_top:
+    resd 1
+section .text
+
+global _trap_handler_1
+_trap_handler_1:
+    cmp dword [esp], sysenter_entry
+    jne .trap
+    mov dword [esp], sysenter_entry_traced
+    and dword [esp + 8], ~0x100
+    iret
+.trap:
+    push dword 0 ; push dummy error code
+    push dword 1
+    jmp common_trap_handler
+
+; Entered through a task gate on a double fault, with a fresh stack
+global double_fault_task
+double_fault_task:
+    ; The limit belongs to whatever stack was in use before
+    mov dword [gs:0x30], 0
+
+    extern double_fault_handler
+    call double_fault_handler
+    jmp $
+
 %macro TRAP_HANDLER 1
     global _trap_handler_%1
 
@@ -49,7 +191,7 @@ ret_from_trap:
 %endmacro
 
 TRAP_HANDLER 0
-TRAP_HANDLER 1
+; See above for the debug trap
 TRAP_HANDLER 2
 TRAP_HANDLER 3
 TRAP_HANDLER 4
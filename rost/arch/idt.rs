@@ -1,16 +1,18 @@
 use core::prelude::*;
 use core::mem::size_of;
 
-use arch::io;
+use arch::{io, gdt};
 
 use arch::RING3;
 
-use exec::tasking;
+use memory::kernel_stack;
+use exec::{tasking, signal};
 
 static PRESENT: u8 = 1 << 7;
 static USER: u8 = RING3 << 5;
 
 static INTERRUPT_GATE: u8 = 0xE;
+static TASK_GATE: u8 = 0x5;
 
 static IDT_SIZE: uint = 256;
 type IdtTable = [IdtEntry, ..IDT_SIZE];
@@ -41,6 +43,22 @@ pub struct Registers {
     pub eip: u32, pub cs: u32, pub eflags: u32, pub useresp: u32, pub ss: u32
 }
 
+impl Registers {
+    /// Returns true if the trap happened while running in ring 3.
+    pub fn from_user(&self) -> bool {
+        self.cs & 0x3 == RING3 as u32
+    }
+
+    pub fn dump(&self) {
+        kprintln!("eax={:08x} ebx={:08x} ecx={:08x} edx={:08x}", self.eax, self.ebx, self.ecx, self.edx);
+        kprintln!("esi={:08x} edi={:08x} ebp={:08x} esp={:08x}", self.esi, self.edi, self.ebp, self.esp);
+        kprintln!("eip={:08x} eflags={:08x} cs={:04x} ss={:04x} useresp={:08x}",
+            self.eip, self.eflags, self.cs, self.ss, self.useresp);
+        kprintln!("ds={:04x} es={:04x} fs={:04x} gs={:04x} int={} err={:x}",
+            self.ds, self.es, self.fs, self.gs, self.int_no, self.err_code);
+    }
+}
+
 impl IdtEntry {
     fn new(handler: u32, selector: u16, flags: u8) -> IdtEntry {
         IdtEntry {
@@ -106,7 +124,41 @@ fn dummy_handler(regs: &mut Registers) {
 }
 
 fn exception_handler(regs: &mut Registers) {
-    panic!("{}, error: {:x}", EXCEPTIONS[regs.int_no as uint], regs.err_code);
+    let name = EXCEPTIONS[regs.int_no as uint];
+
+    if regs.from_user() {
+        kprintln!("Process {} killed: {}, error: {:x} at eip 0x{:x}",
+            tasking::get_current_task().pid, name, regs.err_code, regs.eip);
+        tasking::kill(exception_signal(regs.int_no));
+    }
+
+    regs.dump();
+    panic!("{}, error: {:x}", name, regs.err_code);
+}
+
+// Called by double_fault_task in handlers.asm, running on a stack of its own
+#[no_mangle]
+pub extern fn double_fault_handler() -> ! {
+    let (eip, esp) = gdt::interrupted_state();
+
+    // The fault was raised when pushing the frame of another one
+    if kernel_stack::is_guard(esp) || kernel_stack::is_guard(esp - 4) {
+        panic!("Kernel stack overflow at eip 0x{:x}, esp 0x{:x}", eip, esp);
+    }
+
+    panic!("Double Fault at eip 0x{:x}, esp 0x{:x}", eip, esp);
+}
+
+// The signal a process would have received for the exception
+fn exception_signal(which: u32) -> u32 {
+    match which {
+        0 | 16 | 19 => signal::SIGFPE,
+        1 | 3 => signal::SIGTRAP,
+        6 => signal::SIGILL,
+        11 | 12 | 17 => signal::SIGBUS,
+        4 | 5 | 13 | 14 => signal::SIGSEGV,
+        _ => signal::SIGKILL
+    }
 }
 
 static mut interrupt_handlers: [fn(regs: &mut Registers), ..IDT_SIZE] = [
@@ -123,6 +175,10 @@ pub fn init() {
         for i in range(0, EXCEPTIONS.len()) {
             register_interrupt(i, exception_handler);
         }
+
+        // A double fault is likely caused by an overflowing kernel stack,
+        // switch to another task rather than pushing more onto it
+        entries[8] = IdtEntry::new(0, gdt::DOUBLE_FAULT_SELECTOR, PRESENT | TASK_GATE);
     }
 }
 
@@ -36,3 +36,20 @@ pub fn enable(irq: uint) {
         io::write_port(0x21, curr & !((1u << irq) as u8))
     }
 }
+
+/// Disables interrupts, returning the previous EFLAGS to be handed to
+/// restore_interrupts() once the critical section is over.
+pub fn disable_interrupts() -> u32 {
+    unsafe {
+        let mut eflags: u32;
+        asm!("pushf; pop $0; cli" : "=r"(eflags) ::: "volatile");
+        eflags
+    }
+}
+
+pub fn restore_interrupts(eflags: u32) {
+    // Only the interrupt flag is of interest
+    if eflags & 0x200 != 0 {
+        unsafe { asm!("sti" :::: "volatile"); }
+    }
+}
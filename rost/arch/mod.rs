@@ -2,5 +2,7 @@ pub mod io;
 pub mod gdt;
 pub mod idt;
 pub mod irq;
+pub mod multiboot;
+pub mod cpu;
 
 static RING3: u8 = 3;
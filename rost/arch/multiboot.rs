@@ -0,0 +1,113 @@
+use core::prelude::*;
+
+use memory::KERNEL_BASE;
+
+pub static BOOTLOADER_MAGIC: u32 = 0x2BADB002;
+
+// Memory map entries of this type are free for us to use
+static AVAILABLE: u32 = 1;
+
+bitflags!(
+    #[packed]
+    flags InfoFlags: u32 {
+        static MEMORY       = 1 << 0,
+        #[allow(dead_code)]
+        static BOOT_DEVICE  = 1 << 1,
+        #[allow(dead_code)]
+        static CMDLINE      = 1 << 2,
+        static MODULES      = 1 << 3,
+        #[allow(dead_code)]
+        static AOUT_SYMBOLS = 1 << 4,
+        #[allow(dead_code)]
+        static ELF_SYMBOLS  = 1 << 5,
+        static MEMORY_MAP   = 1 << 6
+    }
+)
+
+#[allow(dead_code)]
+#[packed]
+pub struct Info {
+    flags: u32,
+    mem_lower: u32,
+    mem_upper: u32,
+    boot_device: u32,
+    cmdline: u32,
+    mods_count: u32,
+    mods_addr: u32,
+    syms: [u32, ..4],
+    mmap_length: u32,
+    mmap_addr: u32
+}
+
+#[allow(dead_code)]
+#[packed]
+pub struct MemoryRegion {
+    size: u32, // Size of the entry, not counting this field
+    pub base: u64,
+    pub length: u64,
+    kind: u32
+}
+
+#[allow(dead_code)]
+#[packed]
+pub struct Module {
+    pub start: u32,
+    pub end: u32,
+    pub string: u32,
+    reserved: u32
+}
+
+impl Info {
+    fn flags(&self) -> InfoFlags {
+        InfoFlags::from_bits_truncate(self.flags)
+    }
+
+    /// Returns the amount of memory above 1MB in bytes, if known.
+    pub fn upper_memory(&self) -> Option<u32> {
+        if self.flags().contains(MEMORY) {
+            Some(self.mem_upper * 1024)
+        } else {
+            None
+        }
+    }
+
+    pub fn has_memory_map(&self) -> bool {
+        self.flags().contains(MEMORY_MAP)
+    }
+
+    /// Returns the physical range occupied by the memory map itself.
+    pub fn memory_map_range(&self) -> (u32, u32) {
+        (self.mmap_addr, self.mmap_length)
+    }
+
+    pub fn each_region(&self, f: |&MemoryRegion|) {
+        if !self.has_memory_map() {
+            return;
+        }
+
+        // The bootloader hands out physical addresses
+        let mut addr = self.mmap_addr + KERNEL_BASE;
+        while addr < self.mmap_addr + KERNEL_BASE + self.mmap_length {
+            let region = unsafe { &*(addr as *const MemoryRegion) };
+            f(region);
+            addr += region.size + 4;
+        }
+    }
+
+    pub fn each_module(&self, f: |&Module|) {
+        if !self.flags().contains(MODULES) {
+            return;
+        }
+
+        let modules = (self.mods_addr + KERNEL_BASE) as *const Module;
+        for i in range(0, self.mods_count as int) {
+            unsafe { f(&*modules.offset(i)); }
+        }
+    }
+}
+
+impl MemoryRegion {
+    pub fn is_available(&self) -> bool {
+        self.kind == AVAILABLE
+    }
+}
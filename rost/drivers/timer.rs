@@ -1,14 +1,32 @@
+use core::prelude::*;
+
 use arch::io;
 use arch::idt;
 use arch::irq;
 use drivers::vga;
+use exec::tasking;
+use exec::tasking::Task;
+use util::Unique;
+use util::list::{List, Node, Rawlink};
 
 use core::intrinsics::{volatile_load, volatile_store};
 
-static HZ: u32 = 100;
+static HZ: u32 = 1000;
+
+// Deadlines further out than this, plus the tick sleep_ticks() adds, would
+// already look expired, see expired()
+static MAX_SLEEP_TICKS: u32 = 0x7fffffff;
 
 static mut tick: u32 = 0;
 
+// Tasks waiting for a deadline, ordered by the earliest deadline first
+struct Sleeper {
+    deadline: u32,
+    task: Unique<Task>
+}
+
+static mut sleepers: List<Sleeper> = List { head: None, tail: Rawlink { p: 0 as *mut Node<Sleeper> }, length: 0 };
+
 pub fn init() {
     irq::register_handler(0, timer_handler);
 
@@ -37,14 +55,60 @@ fn increment_ticks() -> u32 {
     }
 }
 
-#[allow(dead_code)]
-pub fn sleep(duration: u32) {
-    let target = read_ticks() + duration / 100;
-    while read_ticks() < target {}
+/// Converts milliseconds to ticks, rounding up so we never sleep too short.
+pub fn ms_to_ticks(ms: u32) -> u32 {
+    ms / 1000 * HZ + (ms % 1000 * HZ + 999) / 1000
+}
+
+/// Blocks the current task for at least the given number of milliseconds.
+pub fn sleep(ms: u32) {
+    sleep_ticks(ms_to_ticks(ms));
+}
+
+pub fn sleep_ticks(ticks: u32) {
+    // Longer sleeps are split up, each part with a deadline we can compare
+    let mut left = ticks;
+    while left > 0 {
+        let part = if left > MAX_SLEEP_TICKS { MAX_SLEEP_TICKS } else { left };
+        // Part of the current tick has already passed, wait for one more
+        let deadline = read_ticks() + part + 1;
+        tasking::block(|task| unsafe {
+            let sleeper = Sleeper { deadline: deadline, task: task };
+            sleepers.insert_when(sleeper, |other| !expired(other.deadline, deadline));
+        });
+        left -= part;
+    }
 }
 
-fn timer_handler(_: &mut idt::Registers) {
-    if increment_ticks() % HZ == 0 {
+// Deadlines are compared as a difference so the tick counter may wrap around
+fn expired(deadline: u32, now: u32) -> bool {
+    (now - deadline) as i32 >= 0
+}
+
+fn wake_sleepers(now: u32) {
+    unsafe {
+        loop {
+            let due = match sleepers.front() {
+                None => false,
+                Some(sleeper) => expired(sleeper.deadline, now)
+            };
+
+            if !due {
+                break;
+            }
+
+            let sleeper = sleepers.pop_front().unwrap();
+            tasking::wake(sleeper.task);
+        }
+    }
+}
+
+fn timer_handler(regs: &mut idt::Registers) {
+    let now = increment_ticks();
+    if now % HZ == 0 {
         vga::puts("\nOne second has passed\n");
     }
+
+    wake_sleepers(now);
+    tasking::tick(regs);
 }
@@ -38,7 +38,8 @@ impl Character {
 pub static ROWS: uint = 25;
 pub static COLS: uint = 80;
 
-static screen: *mut Character = 0xb8000 as *mut Character;
+// The text buffer at 0xb8000, through the kernel's mapping of low memory
+static screen: *mut Character = 0xC00b8000 as *mut Character;
 
 static mut cursor_x: uint = 0;
 static mut cursor_y: uint = 0;
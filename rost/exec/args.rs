@@ -0,0 +1,137 @@
+use core::prelude::*;
+use core::mem::transmute;
+use core::ptr::copy_nonoverlapping_memory;
+use core::raw::Slice;
+use libc::{size_t, c_void};
+
+use memory::malloc::{malloc, free};
+use memory::user::{read_user, strncpy_from_user};
+use exec::errno::{Errno, E2BIG, ENOMEM};
+
+// Total size of all argument and environment strings, terminators included
+pub static ARG_MAX: uint = 4096;
+
+/// The arguments and environment of a program, kept in the kernel while the
+/// address space they came from is replaced.
+pub struct Arguments {
+    // Argument strings followed by the environment, all null terminated
+    buffer: *mut u8,
+    size: uint,
+    argc: uint,
+    envc: uint
+}
+
+impl Arguments {
+    pub fn new() -> Result<Arguments, Errno> {
+        let buffer = unsafe { malloc(ARG_MAX as size_t) as *mut u8 };
+        if buffer.is_null() {
+            return Err(ENOMEM);
+        }
+
+        Ok(Arguments { buffer: buffer, size: 0, argc: 0, envc: 0 })
+    }
+
+    /// Copies the null terminated argv and envp arrays from user space.
+    pub fn from_user(argv: *const *const u8, envp: *const *const u8) -> Result<Arguments, Errno> {
+        let mut args = try!(Arguments::new());
+        try!(args.push_user_strings(argv, false));
+        try!(args.push_user_strings(envp, true));
+        Ok(args)
+    }
+
+    pub fn argc(&self) -> uint { self.argc }
+    pub fn envc(&self) -> uint { self.envc }
+
+    /// Size of all strings including their terminators.
+    pub fn size(&self) -> uint { self.size }
+
+    pub fn push_arg(&mut self, s: *const u8, len: uint) -> Result<(), Errno> {
+        // The environment follows the arguments
+        kassert!(self.envc == 0);
+        try!(self.push(s, len));
+        self.argc += 1;
+        Ok(())
+    }
+
+    pub fn push_env(&mut self, s: *const u8, len: uint) -> Result<(), Errno> {
+        try!(self.push(s, len));
+        self.envc += 1;
+        Ok(())
+    }
+
+    /// Copies all strings to dst, calling f with the address each one ends
+    /// up at. Arguments come first, then the environment.
+    pub unsafe fn copy_to(&self, dst: *mut u8, f: |u32|) {
+        copy_nonoverlapping_memory(dst, self.buffer as *const u8, self.size);
+
+        let mut offset = 0;
+        for _ in range(0, self.argc + self.envc) {
+            f(dst as u32 + offset as u32);
+            while *self.buffer.offset(offset as int) != 0 {
+                offset += 1;
+            }
+            offset += 1;
+        }
+    }
+
+    fn push(&mut self, s: *const u8, len: uint) -> Result<(), Errno> {
+        if self.size + len + 1 > ARG_MAX {
+            return Err(E2BIG);
+        }
+
+        unsafe {
+            let dst = self.buffer.offset(self.size as int);
+            copy_nonoverlapping_memory(dst, s, len);
+            *dst.offset(len as int) = 0;
+        }
+        self.size += len + 1;
+        Ok(())
+    }
+
+    fn push_user_strings(&mut self, array: *const *const u8, env: bool) -> Result<(), Errno> {
+        // A missing array is treated as empty
+        if array.is_null() {
+            return Ok(());
+        }
+
+        let mut i = 0;
+        loop {
+            let s = try!(read_user(unsafe { array.offset(i) }));
+            if s.is_null() {
+                return Ok(());
+            }
+
+            try!(self.push_user(s));
+            if env {
+                self.envc += 1;
+            } else {
+                kassert!(self.envc == 0);
+                self.argc += 1;
+            }
+            i += 1;
+        }
+    }
+
+    // Copies the string straight into the buffer, terminator included
+    fn push_user(&mut self, s: *const u8) -> Result<(), Errno> {
+        let left: &mut [u8] = unsafe {
+            transmute(Slice {
+                data: self.buffer.offset(self.size as int) as *const u8,
+                len: ARG_MAX - self.size
+            })
+        };
+
+        let len = try!(strncpy_from_user(left, s as u32));
+        if len == left.len() {
+            return Err(E2BIG);
+        }
+        self.size += len + 1;
+        Ok(())
+    }
+}
+
+impl Drop for Arguments {
+    fn drop(&mut self) {
+        unsafe { free(self.buffer as *mut c_void); }
+    }
+}
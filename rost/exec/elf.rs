@@ -1,13 +1,50 @@
 use core::prelude::*;
+use core::str;
+use core::mem::{drop, size_of, transmute};
 use core::ptr::{copy_nonoverlapping_memory, set_memory};
+use core::raw::Slice;
 
 use memory;
-use exec::tasking;
+use util::random;
+use exec::{tasking, programs, vdso};
+use exec::args::Arguments;
+use exec::errno::{Errno, ENOENT, ENOEXEC};
 
-// FIXME: Where should the stack go?
-static STACK_POSITION: u32 = 0x5600000;
+static PAGE_SIZE: u32 = 0x1000;
+
+// The part of the stack mapped up front, the rest grows on demand
 static STACK_SIZE: u32 = 8 * 1024;
 
+// The TLS block of the main thread goes right below the stack region,
+// followed by the thread control block which only holds a pointer to itself
+static TLS_MAX: u32 = 0x100000;
+static TLS_POSITION: u32 = memory::STACK_GUARD - TLS_MAX - PAGE_SIZE;
+static TCB_SIZE: u32 = 4;
+
+// Position independent executables and interpreters go here, randomly
+// offset by up to 16MB
+static RANDOMIZE_BASE: bool = true;
+static DYN_BASE: u32 = 0x10000000;
+static INTERP_BASE: u32 = 0x40000000;
+static DYN_BASE_PAGES: u32 = 0x1000;
+
+// Identification values we accept
+static ELFCLASS32: u8 = 1;
+static ELFDATA2LSB: u8 = 1;
+static EV_CURRENT: u32 = 1;
+static EM_386: u16 = 3;
+
+// Auxiliary vector entry types
+static AT_NULL: u32 = 0;
+static AT_PHDR: u32 = 3;
+static AT_PHENT: u32 = 4;
+static AT_PHNUM: u32 = 5;
+static AT_PAGESZ: u32 = 6;
+static AT_BASE: u32 = 7;
+static AT_ENTRY: u32 = 9;
+static AT_RANDOM: u32 = 25;
+static AT_SYSINFO: u32 = 32;
+
 #[allow(dead_code)]
 #[packed]
 struct ELFIdent {
@@ -44,7 +81,8 @@ struct ELFHeader {
 enum ObjectType {
     ET_NONE = 0,
     ET_REL = 1,
-    ET_EXEC = 2
+    ET_EXEC = 2,
+    ET_DYN = 3
 }
 
 #[allow(non_camel_case_types)]
@@ -73,6 +111,35 @@ bitflags!(
     }
 )
 
+#[allow(dead_code)]
+#[packed]
+struct DynamicEntry {
+    d_tag: u32,
+    d_val: u32
+}
+
+// Dynamic section tags
+static DT_NULL: u32 = 0;
+static DT_REL: u32 = 17;
+static DT_RELSZ: u32 = 18;
+static DT_RELENT: u32 = 19;
+
+#[packed]
+struct Relocation {
+    r_offset: u32,
+    r_info: u32
+}
+
+// Relocation types
+static R_386_NONE: u32 = 0;
+static R_386_RELATIVE: u32 = 8;
+
+impl Relocation {
+    fn kind(&self) -> u32 {
+        self.r_info & 0xff
+    }
+}
+
 #[allow(dead_code)]
 #[packed]
 struct ProgramHeader {
@@ -82,21 +149,372 @@ struct ProgramHeader {
     p_paddr: u32,
     p_filesz: u32,
     p_memsz: u32,
-    p_flags: HeaderFlags
+    p_flags: HeaderFlags,
+    p_align: u32
 }
 
-pub fn probe(buffer: *const u8) -> bool {
-    let header = buffer as *const ELFHeader;
-    unsafe { check_magic(&(*header).e_ident) }
+/// Why a file could not be loaded.
+pub enum ElfError {
+    NotElf,
+    WrongClass,
+    WrongEncoding,
+    WrongMachine,
+    WrongVersion,
+    NotExecutable,
+    BadHeaderSize,
+    // Something points outside the file
+    Truncated,
+    BadSegment,
+    // A segment ends up outside of user space
+    BadAddress,
+    UnsupportedSegment(u32),
+    BadDynamic,
+    UnsupportedRelocation(u32),
+    BadInterpreter,
+    BadTls,
+    InterpreterNotFound,
+    NestedInterpreter
+}
+
+impl ElfError {
+    pub fn errno(&self) -> Errno {
+        match *self {
+            InterpreterNotFound => ENOENT,
+            _ => ENOEXEC
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match *self {
+            NotElf => "not an ELF file",
+            WrongClass => "not a 32-bit ELF file",
+            WrongEncoding => "not little endian",
+            WrongMachine => "not an i386 executable",
+            WrongVersion => "unknown ELF version",
+            NotExecutable => "not executable",
+            BadHeaderSize => "unexpected header size",
+            Truncated => "file is truncated",
+            BadSegment => "segment larger in file than in memory",
+            BadAddress => "segment outside of user space",
+            UnsupportedSegment(_) => "unsupported segment type",
+            BadDynamic => "dynamic section outside of the loaded segments",
+            UnsupportedRelocation(_) => "unsupported relocation type",
+            BadInterpreter => "invalid interpreter path",
+            BadTls => "invalid TLS segment",
+            InterpreterNotFound => "interpreter not found",
+            NestedInterpreter => "interpreter needs an interpreter itself"
+        }
+    }
 }
 
-pub fn exec(buffer: *const u8) {
+/// Checks that the file, and its interpreter if it has one, can be loaded.
+/// Once this passes the current address space can safely be given up.
+pub fn validate(file: &'static [u8]) -> Result<(), ElfError> {
     unsafe {
-        let header = buffer as *const ELFHeader;
+        match try!(validate_object(file, DYN_BASE)) {
+            None => validate_relocations(file),
+            Some(path) => {
+                let interp = match programs::lookup(path) {
+                    Some((interp, _)) => interp,
+                    None => return Err(InterpreterNotFound)
+                };
+
+                match try!(validate_object(interp, INTERP_BASE)) {
+                    Some(_) => Err(NestedInterpreter),
+                    None => Ok(())
+                }
+            }
+        }
+    }
+}
+
+// Checks everything the loader relies on, returns the interpreter path if
+// the object asks for one
+unsafe fn validate_object(file: &'static [u8], dyn_base: u32) -> Result<Option<&'static str>, ElfError> {
+    if file.len() < size_of::<ELFHeader>() {
+        return Err(Truncated);
+    }
+
+    let header = file.as_ptr() as *const ELFHeader;
+    let ident = &(*header).e_ident;
+    if !check_magic(ident) {
+        return Err(NotElf);
+    }
+    if ident.ei_class != ELFCLASS32 {
+        return Err(WrongClass);
+    }
+    if ident.ei_data != ELFDATA2LSB {
+        return Err(WrongEncoding);
+    }
+    if ident.ei_version as u32 != EV_CURRENT || (*header).e_version != EV_CURRENT {
+        return Err(WrongVersion);
+    }
+    if (*header).e_machine != EM_386 {
+        return Err(WrongMachine);
+    }
+
+    // Position independent objects may end up anywhere in the base range
+    let kind: u16 = transmute((*header).e_type);
+    let (min_base, max_base) = if kind == ET_EXEC as u16 {
+        (0, 0)
+    } else if kind == ET_DYN as u16 {
+        (dyn_base, dyn_base + (DYN_BASE_PAGES - 1) * PAGE_SIZE)
+    } else {
+        return Err(NotExecutable);
+    };
+
+    if (*header).e_ehsize as uint != size_of::<ELFHeader>()
+            || (*header).e_phentsize as uint != size_of::<ProgramHeader>() {
+        return Err(BadHeaderSize);
+    }
+
+    let header_count = (*header).e_phnum as u32;
+    let header_size = (*header).e_phentsize as u32;
+    if !in_file(file, (*header).e_phoff, header_count * header_size) {
+        return Err(Truncated);
+    }
+
+    if !in_user_space((*header).e_entry, 1, min_base, max_base) {
+        return Err(BadAddress);
+    }
+
+    let mut interp = None;
+    for program_header in program_headers(file.as_ptr()) {
+        let offset = (*program_header).p_offset;
+        let vaddr = (*program_header).p_vaddr;
+        let file_size = (*program_header).p_filesz;
+        let mem_size = (*program_header).p_memsz;
 
-        setup(buffer, header).map(|(entry, stack_top)| {
-            tasking::user_mode(entry, stack_top)
-        });
+        match (*program_header).p_type {
+            PT_NULL | PT_PHDR | PT_GNU_STACK => {},
+            PT_LOAD => {
+                if file_size > mem_size {
+                    return Err(BadSegment);
+                }
+                if !in_file(file, offset, file_size) {
+                    return Err(Truncated);
+                }
+                if !in_user_space(vaddr, mem_size, min_base, max_base) {
+                    return Err(BadAddress);
+                }
+            },
+            PT_DYNAMIC => {
+                if !in_segments(file.as_ptr(), vaddr, mem_size) {
+                    return Err(BadDynamic);
+                }
+            },
+            PT_TLS => {
+                // The initialization image is part of a loaded segment
+                let align = (*program_header).p_align;
+                if file_size > mem_size || !in_segments(file.as_ptr(), vaddr, file_size)
+                        || align > PAGE_SIZE || align & (align - 1) != 0
+                        || mem_size > TLS_MAX {
+                    return Err(BadTls);
+                }
+            },
+            PT_INTERP => {
+                if !in_file(file, offset, file_size) {
+                    return Err(Truncated);
+                }
+                if file_size == 0 || file[(offset + file_size - 1) as uint] != 0 {
+                    return Err(BadInterpreter);
+                }
+                interp = match read_interp(file.as_ptr(), program_header) {
+                    Some(path) => Some(path),
+                    None => return Err(BadInterpreter)
+                };
+            },
+            // Shared library segments have no defined meaning, anything
+            // else we don't know about is only informational like PT_NOTE
+            PT_SHLIB => return Err(UnsupportedSegment(PT_SHLIB as u32)),
+            _ => {}
+        }
+    }
+
+    Ok(interp)
+}
+
+// Without an interpreter the loader applies the relocations itself, see
+// relocate(). Checks it will be able to, reading them from the file.
+unsafe fn validate_relocations(file: &'static [u8]) -> Result<(), ElfError> {
+    let buffer = file.as_ptr();
+
+    let mut dynamic = None;
+    for program_header in program_headers(buffer) {
+        match (*program_header).p_type {
+            PT_DYNAMIC => dynamic = Some((*program_header).p_vaddr),
+            _ => {}
+        }
+    }
+
+    let mut entry = match dynamic {
+        None => return Ok(()),
+        Some(vaddr) => vaddr
+    };
+
+    let entry_size = size_of::<DynamicEntry>() as u32;
+    let mut rel = 0;
+    let mut rel_size = 0;
+    let mut rel_entry = size_of::<Relocation>() as u32;
+    loop {
+        let dynamic = match file_offset(buffer, entry, entry_size) {
+            Some(offset) => buffer.offset(offset as int) as *const DynamicEntry,
+            None => return Err(BadDynamic)
+        };
+
+        match (*dynamic).d_tag {
+            DT_NULL => break,
+            DT_REL => rel = (*dynamic).d_val,
+            DT_RELSZ => rel_size = (*dynamic).d_val,
+            DT_RELENT => rel_entry = (*dynamic).d_val,
+            _ => {}
+        }
+        entry += entry_size;
+    }
+
+    if rel_size == 0 {
+        return Ok(());
+    }
+
+    let table = match file_offset(buffer, rel, rel_size) {
+        Some(offset) if rel_entry >= size_of::<Relocation>() as u32 => offset,
+        _ => return Err(BadDynamic)
+    };
+
+    let mut offset = 0;
+    while offset + rel_entry <= rel_size {
+        let relocation = buffer.offset((table + offset) as int) as *const Relocation;
+        match (*relocation).kind() {
+            R_386_NONE => {},
+            R_386_RELATIVE => {
+                if !in_segments(buffer, (*relocation).r_offset, 4) {
+                    return Err(BadDynamic);
+                }
+            },
+            other => return Err(UnsupportedRelocation(other))
+        }
+        offset += rel_entry;
+    }
+
+    Ok(())
+}
+
+// Where the range, relative to the base, is found in the file. It has to
+// lie in the part of a loadable segment that is read from the file.
+unsafe fn file_offset(buffer: *const u8, vaddr: u32, size: u32) -> Option<u32> {
+    let end = vaddr as u64 + size as u64;
+    for program_header in program_headers(buffer) {
+        match (*program_header).p_type {
+            PT_LOAD => {
+                let start = (*program_header).p_vaddr;
+                if vaddr >= start && end <= start as u64 + (*program_header).p_filesz as u64 {
+                    return Some((*program_header).p_offset + (vaddr - start));
+                }
+            },
+            _ => {}
+        }
+    }
+    None
+}
+
+// Is the range completely inside the file
+fn in_file(file: &[u8], offset: u32, size: u32) -> bool {
+    offset as u64 + size as u64 <= file.len() as u64
+}
+
+// Is the range inside user space wherever between the bases it is loaded,
+// leaving the TLS block and the stack above it alone
+fn in_user_space(vaddr: u32, size: u32, min_base: u32, max_base: u32) -> bool {
+    let start = min_base as u64 + vaddr as u64;
+    let end = max_base as u64 + vaddr as u64 + size as u64;
+    start >= memory::USER_SPACE as u64 && end <= TLS_POSITION as u64
+}
+
+// Is the range covered by one of the loadable segments, relative to the base
+unsafe fn in_segments(buffer: *const u8, vaddr: u32, size: u32) -> bool {
+    let end = vaddr as u64 + size as u64;
+    program_headers(buffer).any(|program_header| {
+        match (*program_header).p_type {
+            PT_LOAD => {
+                let start = (*program_header).p_vaddr as u64;
+                vaddr as u64 >= start && end <= start + (*program_header).p_memsz as u64
+            },
+            _ => false
+        }
+    })
+}
+
+// Walks the program headers of a file whose header has been checked
+struct ProgramHeaders {
+    next: *const u8,
+    size: int,
+    left: uint
+}
+
+impl Iterator<*const ProgramHeader> for ProgramHeaders {
+    fn next(&mut self) -> Option<*const ProgramHeader> {
+        if self.left == 0 {
+            return None;
+        }
+
+        let header = self.next as *const ProgramHeader;
+        self.next = unsafe { self.next.offset(self.size) };
+        self.left -= 1;
+        Some(header)
+    }
+}
+
+unsafe fn program_headers(buffer: *const u8) -> ProgramHeaders {
+    let header = buffer as *const ELFHeader;
+    ProgramHeaders {
+        next: buffer.offset((*header).e_phoff as int),
+        size: (*header).e_phentsize as int,
+        left: (*header).e_phnum as uint
+    }
+}
+
+// What the program needs to know about itself once it's loaded
+struct Image {
+    entry: u32, // Where to start, the interpreter if there is one
+    program_entry: u32,
+    interp_base: u32,
+    phdr: u32,
+    phent: u32,
+    phnum: u32,
+    tls: u32, // Thread pointer of the main thread, if it has TLS
+    brk: u32, // Where the heap starts, right after the program
+    brk_limit: u32 // How far it may grow without running into the interpreter
+}
+
+// A single ELF file loaded into memory
+struct Object {
+    buffer: *const u8,
+    base: u32,
+    end: u32, // End of the highest segment
+    entry: u32,
+    phdr: u32,
+    dynamic: u32,
+    interp: Option<&'static str>,
+    exec_stack: bool,
+    tls: Option<*const ProgramHeader>
+}
+
+/// Loads a validated file into the current, empty, address space and starts
+/// it. Only returns if loading failed, leaving the address space half filled.
+pub fn exec(file: &'static [u8], args: Arguments) -> ElfError {
+    unsafe {
+        match setup(file.as_ptr()) {
+            Ok(image) => {
+                let stack = setup_stack(&image, &args);
+                // We never return here, the arguments live on the stack now
+                drop(args);
+                tasking::set_tls(image.tls);
+                tasking::get_current_task().mm.set_brk_start(image.brk, image.brk_limit);
+                tasking::user_mode(image.entry, stack);
+                unreachable!();
+            },
+            Err(e) => e
+        }
     }
 }
 
@@ -105,63 +523,304 @@ unsafe fn check_magic(ident: *const ELFIdent) -> bool {
     (*ident).ei_mag == MAGIC.as_bytes()
 }
 
-unsafe fn setup(buffer: *const u8, header: *const ELFHeader) -> Option<(u32, u32)> {
-    match (*header).e_type {
-        ET_EXEC => (),
-        _ => {
-            kprintln!("Not executable");
-            return None;
+unsafe fn setup(buffer: *const u8) -> Result<Image, ElfError> {
+    let header = buffer as *const ELFHeader;
+    let program = load_object(buffer, DYN_BASE);
+
+    let mut image = Image {
+        entry: program.entry,
+        program_entry: program.entry,
+        interp_base: 0,
+        phdr: program.phdr,
+        phent: (*header).e_phentsize as u32,
+        phnum: (*header).e_phnum as u32,
+        tls: 0,
+        brk: align_up(program.end, PAGE_SIZE),
+        brk_limit: TLS_POSITION
+    };
+
+    match program.interp {
+        Some(path) => {
+            // The interpreter relocates both itself and the program
+            let interp = match programs::lookup(path) {
+                Some((interp, _)) => load_object(interp.as_ptr(), INTERP_BASE),
+                None => return Err(InterpreterNotFound)
+            };
+            protect_object(&interp);
+
+            image.entry = interp.entry;
+            image.interp_base = interp.base;
+            if interp.base >= image.brk {
+                image.brk_limit = interp.base;
+            }
         },
+        None => {
+            if program.dynamic != 0 {
+                try!(relocate(&program));
+            }
+        }
     }
 
-    let header_count = (*header).e_phnum as int;
-    let header_size = (*header).e_phentsize as int;
-    let header_base = buffer.offset((*header).e_phoff as int);
+    protect_object(&program);
+
+    image.tls = match program.tls {
+        Some(header) => setup_tls(program.base, header),
+        None => 0
+    };
+
+    let stack_flags = if program.exec_stack { memory::EXEC } else { memory::NONE };
+    memory::map(memory::STACK_TOP - STACK_SIZE, STACK_SIZE, memory::USER | memory::WRITE | stack_flags);
+    vdso::map();
+
+    Ok(image)
+}
 
-    // Does this program need an executable stack
-    let mut stack_flags = memory::EXEC;
+// Maps the segments of a validated object, position independent ones are
+// put somewhere above dyn_base. The segments are left writable until
+// protect_object() so relocations can be applied.
+unsafe fn load_object(buffer: *const u8, dyn_base: u32) -> Object {
+    let header = buffer as *const ELFHeader;
+    let base = match (*header).e_type {
+        ET_DYN => load_base(dyn_base),
+        _ => 0
+    };
 
-    for i in range(0, header_count) {
-        let program_header = header_base.offset(i * header_size) as *const ProgramHeader;
+    let mut object = Object {
+        buffer: buffer,
+        base: base,
+        end: 0,
+        entry: base + (*header).e_entry,
+        phdr: 0,
+        dynamic: 0,
+        interp: None,
+        // Does this program need an executable stack
+        exec_stack: true,
+        tls: None
+    };
 
+    for program_header in program_headers(buffer) {
         match (*program_header).p_type {
-            PT_NULL => {}, // Ignore
-            PT_LOAD => load_segment(buffer, program_header),
+            PT_LOAD => {
+                load_segment(buffer, base, program_header);
+
+                let end = base + (*program_header).p_vaddr + (*program_header).p_memsz;
+                if end > object.end {
+                    object.end = end;
+                }
+
+                // Find the program headers in memory unless PT_PHDR tells us
+                let phoff = (*header).e_phoff;
+                let offset = (*program_header).p_offset;
+                if object.phdr == 0 && phoff >= offset && phoff < offset + (*program_header).p_filesz {
+                    object.phdr = base + (*program_header).p_vaddr + (phoff - offset);
+                }
+            },
+            PT_DYNAMIC => object.dynamic = base + (*program_header).p_vaddr,
+            PT_INTERP => object.interp = read_interp(buffer, program_header),
+            PT_PHDR => object.phdr = base + (*program_header).p_vaddr,
+            PT_TLS => object.tls = Some(program_header),
             PT_GNU_STACK => {
                 // We don't need an executable stack if the exec flag is not set
                 if !(*program_header).p_flags.contains(PT_X) {
-                    stack_flags.remove(memory::EXEC);
+                    object.exec_stack = false;
                 }
             },
-            other => {
-                kprintln!("Unsupported ELF segment: 0x{:x}", other as u32);
-                return None;
-            }
+            _ => {}
         }
     }
 
-    memory::map(STACK_POSITION, STACK_SIZE, memory::USER | memory::WRITE | stack_flags);
-    let stack_top = STACK_POSITION + STACK_SIZE;
+    object
+}
 
-    Some(((*header).e_entry, stack_top))
+// Gives the loaded segments the protection they asked for
+unsafe fn protect_object(object: &Object) {
+    for program_header in program_headers(object.buffer) {
+        match (*program_header).p_type {
+            PT_LOAD => protect_segment(object.base, program_header),
+            _ => {}
+        }
+    }
 }
 
-unsafe fn load_segment(buffer: *const u8, header: *const ProgramHeader) {
+// The path of the interpreter is a null terminated string in the file
+unsafe fn read_interp(buffer: *const u8, header: *const ProgramHeader) -> Option<&'static str> {
+    let size = (*header).p_filesz as uint;
+    if size == 0 {
+        return None;
+    }
+
+    let path: &'static [u8] = transmute(Slice {
+        data: buffer.offset((*header).p_offset as int),
+        len: size - 1
+    });
+    str::from_utf8(path)
+}
+
+// Picks the address a position independent object is loaded at
+fn load_base(dyn_base: u32) -> u32 {
+    if RANDOMIZE_BASE {
+        dyn_base + (random::next() % DYN_BASE_PAGES) * PAGE_SIZE
+    } else {
+        dyn_base
+    }
+}
+
+// Applies the relocations listed in the dynamic section. Without an
+// interpreter around only relative relocations can be resolved, which
+// validate_relocations() has made sure of.
+unsafe fn relocate(object: &Object) -> Result<(), ElfError> {
+    let base = object.base;
+    let entry_size = size_of::<DynamicEntry>() as u32;
+
+    let mut rel = 0;
+    let mut rel_size = 0;
+    let mut rel_entry = size_of::<Relocation>() as u32;
+
+    // The dynamic section itself was checked, but not what it points at
+    let mut entry = object.dynamic;
+    loop {
+        if !in_segments(object.buffer, entry - base, entry_size) {
+            return Err(BadDynamic);
+        }
+
+        let dynamic = entry as *const DynamicEntry;
+        match (*dynamic).d_tag {
+            DT_NULL => break,
+            DT_REL => rel = (*dynamic).d_val,
+            DT_RELSZ => rel_size = (*dynamic).d_val,
+            DT_RELENT => rel_entry = (*dynamic).d_val,
+            _ => {}
+        }
+        entry += entry_size;
+    }
+
+    if rel_entry < size_of::<Relocation>() as u32 || !in_segments(object.buffer, rel, rel_size) {
+        return Err(BadDynamic);
+    }
+
+    let mut offset = 0;
+    while offset + rel_entry <= rel_size {
+        let relocation = (base + rel + offset) as *const Relocation;
+        match (*relocation).kind() {
+            R_386_NONE => {},
+            R_386_RELATIVE => {
+                if !in_segments(object.buffer, (*relocation).r_offset, 4) {
+                    return Err(BadDynamic);
+                }
+
+                let target = (base + (*relocation).r_offset) as *mut u32;
+                *target += base;
+            },
+            other => return Err(UnsupportedRelocation(other))
+        }
+        offset += rel_entry;
+    }
+
+    Ok(())
+}
+
+// Sets up the TLS block of the main thread from the initialization image,
+// with the block right below the thread pointer as variant II of the i386
+// ABI wants it. Returns the thread pointer.
+unsafe fn setup_tls(base: u32, header: *const ProgramHeader) -> u32 {
+    let align = if (*header).p_align < 4 { 4 } else { (*header).p_align };
+    let block_size = align_up((*header).p_memsz, align);
+    memory::map(TLS_POSITION, block_size + TCB_SIZE, memory::USER | memory::WRITE);
+
+    // The rest of the block is left zeroed from mapping it
+    copy_nonoverlapping_memory(TLS_POSITION as *mut u8,
+        (base + (*header).p_vaddr) as *const u8, (*header).p_filesz as uint);
+
+    let tp = TLS_POSITION + block_size;
+    *(tp as *mut u32) = tp;
+    tp
+}
+
+fn align_up(value: u32, align: u32) -> u32 {
+    (value + align - 1) & !(align - 1)
+}
+
+// Lays out the initial stack as the System V i386 ABI describes it. From the
+// returned stack pointer and up: argc, argv, NULL, envp, NULL, the auxiliary
+// vector and at the top the strings themselves.
+unsafe fn setup_stack(image: &Image, args: &Arguments) -> u32 {
+    let argc = args.argc();
+    let envc = args.envc();
+
+    let strings = (memory::STACK_TOP - args.size() as u32) & !3;
+    let random_bytes = strings - 16;
+
+    let auxv = [
+        (AT_PHDR, image.phdr),
+        (AT_PHENT, image.phent),
+        (AT_PHNUM, image.phnum),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_BASE, image.interp_base),
+        (AT_ENTRY, image.program_entry),
+        (AT_RANDOM, random_bytes),
+        (AT_SYSINFO, vdso::VDSO_ADDRESS),
+        (AT_NULL, 0)
+    ];
+
+    let words = 1 + argc + 1 + envc + 1 + auxv.len() * 2;
+    let sp = (random_bytes - words as u32 * 4) & !0xf;
+
+    let argv = (sp + 4) as *mut u32;
+    let envp = argv.offset(argc as int + 1);
+
+    let mut i = 0;
+    args.copy_to(strings as *mut u8, |addr| {
+        if i < argc {
+            *argv.offset(i as int) = addr;
+        } else {
+            *envp.offset((i - argc) as int) = addr;
+        }
+        i += 1;
+    });
+    *argv.offset(argc as int) = 0;
+    *envp.offset(envc as int) = 0;
+
+    let mut entry = envp.offset(envc as int + 1);
+    for &(kind, value) in auxv.iter() {
+        *entry = kind;
+        *entry.offset(1) = value;
+        entry = entry.offset(2);
+    }
+
+    for i in range(0, 16) {
+        *(random_bytes as *mut u8).offset(i) = random::next() as u8;
+    }
+
+    *(sp as *mut u32) = argc as u32;
+    sp
+}
+
+unsafe fn load_segment(buffer: *const u8, base: u32, header: *const ProgramHeader) {
     let mem_size = (*header).p_memsz as uint; // Size in memory
     let file_size = (*header).p_filesz as uint; // Size in file
-    let mem_pos = (*header).p_vaddr as *mut u8; // Position in memory
+    let mem_pos = (base + (*header).p_vaddr) as *mut u8; // Position in memory
     let file_pos = (*header).p_offset as int; // Position in file
 
-    memory::map(mem_pos as u32, mem_size as u32, memory::USER | translate_flags(header));
+    // Map the segment writable while we fill it in, the kernel can't write
+    // to read-only pages either
+    memory::map(mem_pos as u32, mem_size as u32, memory::USER | memory::WRITE);
 
     copy_nonoverlapping_memory(mem_pos, buffer.offset(file_pos as int), file_size as uint);
-    set_memory(mem_pos.offset(file_pos + file_size as int), 0, mem_size - file_size);
+    set_memory(mem_pos.offset(file_size as int), 0, mem_size - file_size);
+}
+
+unsafe fn protect_segment(base: u32, header: *const ProgramHeader) {
+    let mem_pos = base + (*header).p_vaddr;
+    memory::protect(mem_pos, (*header).p_memsz, memory::USER | translate_flags(header));
 }
 
 unsafe fn translate_flags(header: *const ProgramHeader) -> memory::Flags {
+    let mut flags = memory::NONE;
     if (*header).p_flags.contains(PT_W) {
-        memory::WRITE
-    } else {
-        memory::NONE
+        flags.insert(memory::WRITE);
+    }
+    if (*header).p_flags.contains(PT_X) {
+        flags.insert(memory::EXEC);
     }
+    flags
 }
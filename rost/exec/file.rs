@@ -0,0 +1,161 @@
+use core::prelude::*;
+use core::mem::zeroed;
+use alloc::rc::Rc;
+use alloc::owned::Box;
+
+use kernel::console::AnsiConsole;
+use exec::errno::{Errno, EBADF, EMFILE, ESPIPE, ENOTTY};
+
+/// Highest number of descriptors a process may have open.
+pub static MAX_FILES: uint = 64;
+
+/// Something a file descriptor refers to. Operations a file doesn't support
+/// fail the way they do on Linux.
+pub trait File {
+    fn read(&self, _buf: &mut [u8]) -> Result<uint, Errno> {
+        Err(EBADF)
+    }
+
+    fn write(&self, _buf: &[u8]) -> Result<uint, Errno> {
+        Err(EBADF)
+    }
+
+    /// Moves the offset the way lseek() does, returning the new one.
+    fn seek(&self, _offset: i32, _whence: u32) -> Result<u32, Errno> {
+        Err(ESPIPE)
+    }
+
+    fn ioctl(&self, _request: u32, _arg: u32) -> Result<u32, Errno> {
+        Err(ENOTTY)
+    }
+
+    /// Called once no descriptor refers to the file anymore.
+    fn close(&self) {}
+}
+
+/// An open file, shared by descriptors made with dup() or inherited
+/// through fork(). Closed when the last of them goes away.
+pub struct OpenFile {
+    file: Box<File>
+}
+
+impl OpenFile {
+    pub fn new(file: Box<File>) -> Rc<OpenFile> {
+        Rc::new(OpenFile { file: file })
+    }
+}
+
+impl Deref<Box<File>> for OpenFile {
+    fn deref<'a>(&'a self) -> &'a Box<File> {
+        &self.file
+    }
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        self.file.close();
+    }
+}
+
+/// The console, what stdin, stdout and stderr start out as. There's no
+/// input plumbed through to processes yet, reading always hits the end.
+pub struct ConsoleFile;
+
+impl File for ConsoleFile {
+    fn read(&self, _buf: &mut [u8]) -> Result<uint, Errno> {
+        Ok(0)
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<uint, Errno> {
+        for &c in buf.iter() {
+            AnsiConsole.print(c as char);
+        }
+        Ok(buf.len())
+    }
+}
+
+/// The open files of a process, indexed by descriptor.
+pub struct FileTable {
+    files: [Option<Rc<OpenFile>>, ..MAX_FILES]
+}
+
+impl FileTable {
+    /// A table without any open files. None is all zeroes, which the zeroed
+    /// Task relies on too.
+    pub fn new() -> FileTable {
+        FileTable { files: unsafe { zeroed() } }
+    }
+
+    /// Opens the console as stdin, stdout and stderr.
+    pub fn open_console(&mut self) {
+        let console = OpenFile::new(box ConsoleFile as Box<File>);
+        for fd in range(0, 3u) {
+            self.files[fd] = Some(console.clone());
+        }
+    }
+
+    /// Shares every open file with a forked child.
+    pub fn clone(&self) -> FileTable {
+        let mut table = FileTable::new();
+        for (fd, file) in self.files.iter().enumerate() {
+            table.files[fd] = file.clone();
+        }
+        table
+    }
+
+    pub fn get(&self, fd: u32) -> Result<Rc<OpenFile>, Errno> {
+        if fd as uint >= MAX_FILES {
+            return Err(EBADF);
+        }
+
+        match self.files[fd as uint] {
+            Some(ref file) => Ok(file.clone()),
+            None => Err(EBADF)
+        }
+    }
+
+    /// Gives the file the lowest free descriptor.
+    pub fn add(&mut self, file: Rc<OpenFile>) -> Result<u32, Errno> {
+        match self.files.iter().position(|file| file.is_none()) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                Ok(fd as u32)
+            },
+            None => Err(EMFILE)
+        }
+    }
+
+    pub fn dup(&mut self, fd: u32) -> Result<u32, Errno> {
+        let file = try!(self.get(fd));
+        self.add(file)
+    }
+
+    /// Makes new_fd refer to the same file as old_fd, closing whatever it
+    /// referred to before.
+    pub fn dup2(&mut self, old_fd: u32, new_fd: u32) -> Result<u32, Errno> {
+        let file = try!(self.get(old_fd));
+        if new_fd as uint >= MAX_FILES {
+            return Err(EBADF);
+        }
+
+        self.files[new_fd as uint] = Some(file);
+        Ok(new_fd)
+    }
+
+    pub fn close(&mut self, fd: u32) -> Result<(), Errno> {
+        if fd as uint >= MAX_FILES {
+            return Err(EBADF);
+        }
+
+        match self.files[fd as uint].take() {
+            Some(_) => Ok(()),
+            None => Err(EBADF)
+        }
+    }
+
+    pub fn close_all(&mut self) {
+        for file in self.files.mut_iter() {
+            *file = None;
+        }
+    }
+}
@@ -0,0 +1,273 @@
+use core::prelude::*;
+
+use memory;
+use memory::Flags;
+use util::list::List;
+use exec::errno::{Errno, EINVAL, ENOMEM};
+
+static PAGE_SIZE: u32 = 0x1000;
+
+// Mappings without an address go somewhere in here, well above where
+// the program and its interpreter are loaded
+static MMAP_BASE: u32 = 0x50000000;
+static MMAP_END: u32 = 0xA0000000;
+
+pub static PROT_NONE: u32 = 0;
+pub static PROT_READ: u32 = 1;
+pub static PROT_WRITE: u32 = 2;
+pub static PROT_EXEC: u32 = 4;
+
+pub static MAP_SHARED: u32 = 0x01;
+pub static MAP_PRIVATE: u32 = 0x02;
+pub static MAP_FIXED: u32 = 0x10;
+pub static MAP_ANONYMOUS: u32 = 0x20;
+
+// A range of anonymous memory, pages are only mapped once touched
+struct Region {
+    start: u32,
+    end: u32,
+    flags: Flags
+}
+
+/// The mappings a process has made through brk() and mmap(). Everything
+/// here is demand-zero, see fault().
+pub struct MemoryMap {
+    regions: List<Region>,
+    brk_start: u32,
+    brk: u32,
+    // The heap stops where the next object is loaded, or at MMAP_BASE
+    brk_limit: u32
+}
+
+impl MemoryMap {
+    /// Forgets about every region, the pages are expected to be gone already.
+    pub fn clear(&mut self) {
+        loop {
+            match self.regions.pop_front() {
+                None => break,
+                Some(_) => {}
+            }
+        }
+        self.brk_start = 0;
+        self.brk = 0;
+        self.brk_limit = 0;
+    }
+
+    /// Lets the heap start at the page aligned address. It may grow up to
+    /// limit, where whatever is loaded above the program begins.
+    pub fn set_brk_start(&mut self, addr: u32, limit: u32) {
+        self.brk_start = addr;
+        self.brk = addr;
+        self.brk_limit = if limit < MMAP_BASE { limit } else { MMAP_BASE };
+    }
+
+    /// Copies the map for a forked child, the pages themselves are shared
+    /// by memory::clone_directory().
+    pub fn clone(&self) -> MemoryMap {
+        let mut regions = List::new();
+        for region in self.regions.iter() {
+            regions.append(Region { start: region.start, end: region.end, flags: region.flags });
+        }
+
+        MemoryMap {
+            regions: regions,
+            brk_start: self.brk_start,
+            brk: self.brk,
+            brk_limit: self.brk_limit
+        }
+    }
+
+    /// Moves the end of the heap, returning the new end. Like Linux the old
+    /// end is returned if it can't be moved.
+    pub fn brk(&mut self, addr: u32) -> u32 {
+        if addr < self.brk_start || addr > self.brk_limit {
+            return self.brk;
+        }
+
+        let old_end = page_align(self.brk);
+        let new_end = page_align(addr);
+
+        if new_end > old_end {
+            if self.overlaps(old_end, new_end) {
+                return self.brk;
+            }
+            self.update(old_end, new_end, Some(memory::USER | memory::WRITE));
+        } else if new_end < old_end {
+            self.update(new_end, old_end, None);
+            memory::unmap(new_end, old_end - new_end);
+        }
+
+        self.brk = addr;
+        self.brk
+    }
+
+    /// Maps anonymous memory, only private mappings are supported.
+    pub fn mmap(&mut self, addr: u32, len: u32, prot: u32, flags: u32) -> Result<u32, Errno> {
+        if len == 0 || flags & MAP_ANONYMOUS == 0 || flags & (MAP_SHARED | MAP_PRIVATE) != MAP_PRIVATE {
+            return Err(EINVAL);
+        }
+
+        let start = if flags & MAP_FIXED != 0 {
+            let size = try!(check_range(addr, len));
+
+            // Whatever was there before is replaced
+            self.unmap(addr, size);
+            addr
+        } else {
+            if len > MMAP_END - MMAP_BASE {
+                return Err(ENOMEM);
+            }
+
+            match self.find_free(page_align(len)) {
+                Some(start) => start,
+                None => return Err(ENOMEM)
+            }
+        };
+
+        self.update(start, start + page_align(len), Some(prot_flags(prot)));
+        Ok(start)
+    }
+
+    pub fn munmap(&mut self, addr: u32, len: u32) -> Result<(), Errno> {
+        if len == 0 {
+            return Err(EINVAL);
+        }
+
+        let size = try!(check_range(addr, len));
+        self.unmap(addr, size);
+        Ok(())
+    }
+
+    /// Changes the protection of the range, along with any pages already
+    /// mapped in it that aren't part of a region such as the program's.
+    pub fn mprotect(&mut self, addr: u32, len: u32, prot: u32) -> Result<(), Errno> {
+        let size = try!(check_range(addr, len));
+        let flags = prot_flags(prot);
+        self.update_existing(addr, addr + size, flags);
+        memory::protect(addr, size, flags);
+        Ok(())
+    }
+
+    /// Returns the flags the page at addr should be mapped with on first
+    /// access, if it's part of an accessible region.
+    pub fn fault(&self, addr: u32) -> Option<Flags> {
+        for region in self.regions.iter() {
+            if addr >= region.start && addr < region.end {
+                if region.flags.contains(memory::USER) {
+                    return Some(region.flags);
+                }
+                return None;
+            }
+        }
+        None
+    }
+
+    fn unmap(&mut self, start: u32, size: u32) {
+        self.update(start, start + size, None);
+        memory::unmap(start, size);
+    }
+
+    fn overlaps(&self, start: u32, end: u32) -> bool {
+        self.regions.iter().any(|region| region.start < end && region.end > start)
+    }
+
+    // First fit between MMAP_BASE and MMAP_END, the regions are sorted.
+    // Regions may end right below 4GB, so the arithmetic is done in u64.
+    fn find_free(&self, size: u32) -> Option<u32> {
+        let size = size as u64;
+        let mut start = MMAP_BASE as u64;
+        for region in self.regions.iter() {
+            if region.end as u64 <= start {
+                continue;
+            }
+            if region.start as u64 >= start + size {
+                break;
+            }
+            start = region.end as u64;
+        }
+
+        if start + size <= MMAP_END as u64 { Some(start as u32) } else { None }
+    }
+
+    // Gives [start, end) the flags, or removes it from the map if there are
+    // none. Regions only partly covered are split.
+    fn update(&mut self, start: u32, end: u32, flags: Option<Flags>) {
+        let mut kept = List::new();
+        loop {
+            let region = match self.regions.pop_front() {
+                None => break,
+                Some(region) => region
+            };
+
+            if region.end <= start || region.start >= end {
+                kept.append(region);
+                continue;
+            }
+
+            if region.start < start {
+                kept.append(Region { start: region.start, end: start, flags: region.flags });
+            }
+            if region.end > end {
+                kept.append(Region { start: end, end: region.end, flags: region.flags });
+            }
+        }
+        self.regions = kept;
+
+        match flags {
+            None => {},
+            Some(flags) => {
+                self.regions.insert_when(Region { start: start, end: end, flags: flags },
+                    |region| region.start > start);
+            }
+        }
+    }
+
+    // Like update() but only for the parts of [start, end) already in a region
+    fn update_existing(&mut self, start: u32, end: u32, flags: Flags) {
+        let mut covered = List::new();
+        for region in self.regions.iter() {
+            if region.end > start && region.start < end {
+                let from = if region.start > start { region.start } else { start };
+                let to = if region.end < end { region.end } else { end };
+                covered.append((from, to));
+            }
+        }
+
+        loop {
+            match covered.pop_front() {
+                None => break,
+                Some((from, to)) => self.update(from, to, Some(flags))
+            }
+        }
+    }
+}
+
+fn prot_flags(prot: u32) -> Flags {
+    if prot == PROT_NONE {
+        return memory::NONE;
+    }
+
+    let mut flags = memory::USER;
+    if prot & PROT_WRITE != 0 {
+        flags.insert(memory::WRITE);
+    }
+    if prot & PROT_EXEC != 0 {
+        flags.insert(memory::EXEC);
+    }
+    flags
+}
+
+// The range must be page aligned and inside the part of user space processes
+// may map themselves. Returns the size rounded up to whole pages.
+fn check_range(addr: u32, len: u32) -> Result<u32, Errno> {
+    let end = addr as u64 + len as u64;
+    if addr & (PAGE_SIZE - 1) != 0 || addr < memory::USER_SPACE || end > memory::STACK_GUARD as u64 {
+        return Err(EINVAL);
+    }
+
+    Ok(page_align(len))
+}
+
+fn page_align(value: u32) -> u32 {
+    (value + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
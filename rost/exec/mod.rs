@@ -1,3 +1,10 @@
 pub mod tasking;
 pub mod elf;
 pub mod syscalls;
+pub mod errno;
+pub mod signal;
+pub mod programs;
+pub mod args;
+pub mod mm;
+pub mod file;
+pub mod vdso;
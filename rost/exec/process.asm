@@ -1,4 +1,11 @@
 global run_iret
 run_iret:
     add esp, 0x4
+    ; Switch to the user segments last, the kernel relies on its own gs
+    mov ax, 0x23
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    pop eax
+    mov gs, ax
     iret
@@ -0,0 +1,35 @@
+use core::prelude::*;
+use core::mem::transmute;
+use core::raw::Slice;
+
+use exec::syscalls::{Personality, Rost};
+
+// The programs are linked into the kernel image, see %.embed in the Makefile
+extern {
+    static _binary_do_nothing_elf_start: u8;
+    static _binary_do_nothing_elf_end: u8;
+    static _binary_hello_world_elf_start: u8;
+    static _binary_hello_world_elf_end: u8;
+    static _binary_test_fork_elf_start: u8;
+    static _binary_test_fork_elf_end: u8;
+    static _binary_bench_syscall_elf_start: u8;
+    static _binary_bench_syscall_elf_end: u8;
+}
+
+/// Finds the embedded program with the given path, along with the syscall
+/// numbering it was built for. Ours use the one from programs/syscalls.h,
+/// unmodified Linux binaries get Linux.
+pub fn lookup(path: &str) -> Option<(&'static [u8], Personality)> {
+    let (start, end, personality) = match path {
+        "/bin/do_nothing" => (&_binary_do_nothing_elf_start, &_binary_do_nothing_elf_end, Rost),
+        "/bin/hello_world" => (&_binary_hello_world_elf_start, &_binary_hello_world_elf_end, Rost),
+        "/bin/test_fork" => (&_binary_test_fork_elf_start, &_binary_test_fork_elf_end, Rost),
+        "/bin/bench_syscall" => (&_binary_bench_syscall_elf_start, &_binary_bench_syscall_elf_end, Rost),
+        _ => return None
+    };
+
+    let start = start as *const u8;
+    let end = end as *const u8;
+    let file = unsafe { transmute(Slice { data: start, len: end as uint - start as uint }) };
+    Some((file, personality))
+}
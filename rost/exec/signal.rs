@@ -0,0 +1,8 @@
+// There is no signal delivery, these are only used to tell a parent why its
+// child was terminated
+pub static SIGILL: u32 = 4;
+pub static SIGTRAP: u32 = 5;
+pub static SIGBUS: u32 = 7;
+pub static SIGFPE: u32 = 8;
+pub static SIGKILL: u32 = 9;
+pub static SIGSEGV: u32 = 11;
@@ -1,46 +1,105 @@
 use core::prelude::*;
+use core::{str, u32};
 
-use arch::idt;
+use arch::{gdt, idt};
+use drivers::timer;
+use memory::user::{copy_from_user, copy_to_user, strncpy_from_user, read_user, write_user};
 use exec::tasking;
+use exec::args::Arguments;
+use exec::file::{File, OpenFile};
+use exec::errno::{Errno, EINVAL, ENOENT, ENOSYS, ENAMETOOLONG};
 
-static NUM_SYSCALLS: uint = 128;
+static WNOHANG: u32 = 1;
+
+// Size of the i386 struct rusage, two timevals and fourteen longs
+static RUSAGE_SIZE: uint = 72;
+
+// Longest path accepted from user space, including the terminator
+static PATH_MAX: uint = 256;
+
+static NUM_SYSCALLS: uint = 384;
+
+/// Decides which numbers int 0x80 follows for a task. Our own programs use
+/// the numbering from programs/syscalls.h, Linux binaries that of i386 Linux.
+/// Set on every execve from the table in programs::lookup().
+#[deriving(PartialEq)]
+pub enum Personality {
+    Rost,
+    Linux
+}
+
+// Values taken by personality(), Linux only uses the low byte up to 0x1f
+static PER_LINUX: u32 = 0x00;
+static PER_ROST: u32 = 0xff;
+static PER_QUERY: u32 = 0xffffffff;
 
 static mut syscalls: [fn(regs: &mut idt::Registers), ..NUM_SYSCALLS] = [
     unimplemented_syscall, ..NUM_SYSCALLS
 ];
 
+static mut linux_syscalls: [fn(regs: &mut idt::Registers), ..NUM_SYSCALLS] = [
+    unimplemented_syscall, ..NUM_SYSCALLS
+];
+
+// Every syscall evaluates to a Result, handed back in eax as the value on
+// success and as a negative errno on failure. The body is a function of its
+// own so try!() and return can be used in it.
 macro_rules! syscall (
     // no args
-    (fn $name:ident() -> $ret:ty $func:expr) => (
+    (fn $name:ident() $func:expr) => (
         fn $name(regs: &mut idt::Registers) {
-            regs.eax = { $func } as $ret;
+            fn call() -> Result<u32, Errno> { $func }
+            regs.eax = to_return(call());
         }
     );
     // 1 arg
     (fn $name:ident($a0:ident: $t0:ty) $func:expr) => (
         fn $name(regs: &mut idt::Registers) {
-            let $a0 = regs.ebx as $t0;
-            $func
+            fn call($a0: $t0) -> Result<u32, Errno> { $func }
+            regs.eax = to_return(call(regs.ebx as $t0));
         }
     );
-    // 3 args
-    (fn $name:ident($a0:ident: $t0:ty, $a1:ident: $t1:ty, $a2:ident: $t2:ty) -> $ret:ty $func:expr) => (
+    // 2 args
+    (fn $name:ident($a0:ident: $t0:ty, $a1:ident: $t1:ty) $func:expr) => (
         fn $name(regs: &mut idt::Registers) {
-            let $a0 = regs.ebx as $t0;
-            let $a1 = regs.ecx as $t1;
-            let $a2 = regs.edx as $t2;
-            regs.eax = { $func } as $ret;
+            fn call($a0: $t0, $a1: $t1) -> Result<u32, Errno> { $func }
+            regs.eax = to_return(call(regs.ebx as $t0, regs.ecx as $t1));
         }
     );
+    // 3 args
     (fn $name:ident($a0:ident: $t0:ty, $a1:ident: $t1:ty, $a2:ident: $t2:ty) $func:expr) => (
         fn $name(regs: &mut idt::Registers) {
-            let $a0 = regs.ebx as $t0;
-            let $a1 = regs.ecx as $t1;
-            let $a2 = regs.edx as $t2;
-            $func
+            fn call($a0: $t0, $a1: $t1, $a2: $t2) -> Result<u32, Errno> { $func }
+            regs.eax = to_return(call(regs.ebx as $t0, regs.ecx as $t1, regs.edx as $t2));
+        }
+    );
+    // 4 args
+    (fn $name:ident($a0:ident: $t0:ty, $a1:ident: $t1:ty, $a2:ident: $t2:ty,
+                    $a3:ident: $t3:ty) $func:expr) => (
+        fn $name(regs: &mut idt::Registers) {
+            fn call($a0: $t0, $a1: $t1, $a2: $t2, $a3: $t3) -> Result<u32, Errno> { $func }
+            regs.eax = to_return(call(regs.ebx as $t0, regs.ecx as $t1, regs.edx as $t2,
+                regs.esi as $t3));
+        }
+    );
+    // 5 args
+    (fn $name:ident($a0:ident: $t0:ty, $a1:ident: $t1:ty, $a2:ident: $t2:ty,
+                    $a3:ident: $t3:ty, $a4:ident: $t4:ty) $func:expr) => (
+        fn $name(regs: &mut idt::Registers) {
+            fn call($a0: $t0, $a1: $t1, $a2: $t2, $a3: $t3, $a4: $t4) -> Result<u32, Errno> { $func }
+            regs.eax = to_return(call(regs.ebx as $t0, regs.ecx as $t1, regs.edx as $t2,
+                regs.esi as $t3, regs.edi as $t4));
+        }
+    );
+    // 6 args
+    (fn $name:ident($a0:ident: $t0:ty, $a1:ident: $t1:ty, $a2:ident: $t2:ty,
+                    $a3:ident: $t3:ty, $a4:ident: $t4:ty, $a5:ident: $t5:ty) $func:expr) => (
+        fn $name(regs: &mut idt::Registers) {
+            fn call($a0: $t0, $a1: $t1, $a2: $t2, $a3: $t3, $a4: $t4, $a5: $t5) -> Result<u32, Errno> { $func }
+            regs.eax = to_return(call(regs.ebx as $t0, regs.ecx as $t1, regs.edx as $t2,
+                regs.esi as $t3, regs.edi as $t4, regs.ebp as $t5));
         }
     );
-
 )
 
 pub fn init() {
@@ -49,6 +108,47 @@ pub fn init() {
         syscalls[2] = syscall_write;
         syscalls[3] = syscall_fork;
         syscalls[4] = syscall_sleep;
+        syscalls[5] = syscall_nanosleep;
+        syscalls[6] = syscall_waitpid;
+        syscalls[7] = syscall_execve;
+        syscalls[8] = syscall_set_thread_area;
+        syscalls[9] = syscall_brk;
+        syscalls[10] = syscall_mmap;
+        syscalls[11] = syscall_munmap;
+        syscalls[12] = syscall_mprotect;
+        syscalls[13] = syscall_read;
+        syscalls[14] = syscall_close;
+        syscalls[15] = syscall_dup;
+        syscalls[16] = syscall_dup2;
+        syscalls[17] = syscall_lseek;
+        syscalls[136] = syscall_personality;
+
+        // The common subset of i386 Linux, enough for statically linked programs
+        linux_syscalls[1] = syscall_exit;
+        linux_syscalls[2] = syscall_fork;
+        linux_syscalls[3] = syscall_read;
+        linux_syscalls[4] = syscall_write;
+        linux_syscalls[5] = syscall_open;
+        linux_syscalls[6] = syscall_close;
+        linux_syscalls[7] = syscall_waitpid;
+        linux_syscalls[11] = syscall_execve;
+        linux_syscalls[19] = syscall_lseek;
+        linux_syscalls[20] = syscall_getpid;
+        linux_syscalls[41] = syscall_dup;
+        linux_syscalls[45] = syscall_brk;
+        linux_syscalls[54] = syscall_ioctl;
+        linux_syscalls[63] = syscall_dup2;
+        linux_syscalls[64] = syscall_getppid;
+        linux_syscalls[91] = syscall_munmap;
+        linux_syscalls[114] = syscall_wait4;
+        linux_syscalls[125] = syscall_mprotect;
+        linux_syscalls[136] = syscall_personality;
+        linux_syscalls[146] = syscall_writev;
+        linux_syscalls[162] = syscall_nanosleep;
+        linux_syscalls[192] = syscall_mmap;
+        linux_syscalls[243] = syscall_set_thread_area;
+        linux_syscalls[252] = syscall_exit;
+        linux_syscalls[258] = syscall_set_tid_address;
     }
 
     idt::register_user_interrupt(0x80, syscall_handler);
@@ -59,44 +159,301 @@ fn syscall_handler(regs: &mut idt::Registers) {
     if index as uint >= NUM_SYSCALLS {
         unimplemented_syscall(regs);
     } else {
-        unsafe { syscalls[index as uint](regs); }
+        unsafe {
+            match tasking::get_current_task().personality {
+                Rost => syscalls[index as uint](regs),
+                Linux => linux_syscalls[index as uint](regs)
+            }
+        }
+    }
+}
+
+fn to_return(result: Result<u32, Errno>) -> u32 {
+    match result {
+        Ok(value) => value,
+        Err(e) => e.to_return()
     }
 }
 
 fn unimplemented_syscall(regs: &mut idt::Registers) {
-    kprintln!("Unimplemented syscall, number={}", regs.eax);
+    klog!("Unimplemented syscall, number={}", regs.eax);
+    regs.eax = ENOSYS.to_return();
 }
 
 syscall!(fn syscall_exit(code: u32) {
     let pid = tasking::get_current_task().pid;
     kprintln!("Process {} exit with code {}", pid, code);
-    tasking::kill();
+    tasking::exit(code)
+})
+
+syscall!(fn syscall_write(fd: u32, data: u32, len: u32) {
+    let file = try!(tasking::get_current_task().files.get(fd));
+    write_file(&*file, data, len)
+})
+
+// Hands the data to the file a chunk at a time. If a bad address is hit
+// the part before it is still written.
+fn write_file(file: &OpenFile, data: u32, len: u32) -> Result<u32, Errno> {
+    let mut buffer = [0u8, ..256];
+    let mut written = 0;
+    while written < len {
+        let chunk = if len - written < buffer.len() as u32 { len - written } else { buffer.len() as u32 };
+        match copy_from_user(buffer.as_mut_ptr(), data + written, chunk as uint) {
+            Ok(()) => {},
+            Err(e) => return partial(written, e)
+        }
+
+        let count = match file.write(buffer.slice_to(chunk as uint)) {
+            Ok(count) => count as u32,
+            Err(e) => return partial(written, e)
+        };
+        written += count;
+        if count < chunk {
+            break;
+        }
+    }
+
+    Ok(written)
+}
+
+// Like write_file(), what was read before a bad address is kept
+fn read_file(file: &OpenFile, data: u32, len: u32) -> Result<u32, Errno> {
+    let mut buffer = [0u8, ..256];
+    let mut read = 0;
+    while read < len {
+        let chunk = if len - read < buffer.len() as u32 { len - read } else { buffer.len() as u32 };
+        let count = match file.read(buffer.mut_slice_to(chunk as uint)) {
+            Ok(count) => count,
+            Err(e) => return partial(read, e)
+        };
+
+        match copy_to_user(data + read, buffer.as_ptr(), count) {
+            Ok(()) => {},
+            Err(e) => return partial(read, e)
+        }
+        read += count as u32;
+        if count < chunk as uint {
+            break;
+        }
+    }
+
+    Ok(read)
+}
+
+// A transfer that fails part way reports what was done so far
+fn partial(done: u32, error: Errno) -> Result<u32, Errno> {
+    if done > 0 { Ok(done) } else { Err(error) }
+}
+
+#[allow(dead_code)]
+#[packed]
+struct Iovec {
+    base: u32,
+    len: u32
+}
+
+syscall!(fn syscall_writev(fd: u32, iov: *const Iovec, count: i32) {
+    let file = try!(tasking::get_current_task().files.get(fd));
+    if count < 0 || count > 1024 {
+        return Err(EINVAL);
+    }
+
+    let mut total = 0;
+    for i in range(0, count as int) {
+        let vec = try!(read_user(unsafe { iov.offset(i) }));
+        let written = try!(write_file(&*file, vec.base, vec.len));
+        total += written;
+        if written < vec.len {
+            break;
+        }
+    }
+    Ok(total)
+})
+
+syscall!(fn syscall_read(fd: u32, data: u32, len: u32) {
+    let file = try!(tasking::get_current_task().files.get(fd));
+    read_file(&*file, data, len)
 })
 
-syscall!(fn syscall_write(fd: u32, data: *const u8, len: u32) -> u32 {
-    use kernel::console::AnsiConsole;
-    kassert!(fd == 1);
+syscall!(fn syscall_lseek(fd: u32, offset: i32, whence: u32) {
+    let file = try!(tasking::get_current_task().files.get(fd));
+    file.seek(offset, whence)
+})
+
+// There is no file system
+syscall!(fn syscall_open(path: *const u8, _flags: u32, _mode: u32) {
+    let mut buffer = [0u8, ..PATH_MAX];
+    let len = try!(strncpy_from_user(&mut buffer, path as u32));
+    if len == buffer.len() {
+        return Err(ENAMETOOLONG);
+    }
+    Err(ENOENT)
+})
+
+syscall!(fn syscall_close(fd: u32) {
+    try!(tasking::get_current_task().files.close(fd));
+    Ok(0)
+})
+
+syscall!(fn syscall_dup(fd: u32) {
+    tasking::get_current_task().files.dup(fd)
+})
+
+syscall!(fn syscall_dup2(old_fd: u32, new_fd: u32) {
+    tasking::get_current_task().files.dup2(old_fd, new_fd)
+})
+
+syscall!(fn syscall_ioctl(fd: u32, request: u32, arg: u32) {
+    let file = try!(tasking::get_current_task().files.get(fd));
+    file.ioctl(request, arg)
+})
+
+syscall!(fn syscall_getpid() {
+    Ok(tasking::get_current_task().pid as u32)
+})
+
+syscall!(fn syscall_getppid() {
+    Ok(tasking::get_current_task().ppid as u32)
+})
+
+// There are no threads to clear the address for, just hand back the tid
+syscall!(fn syscall_set_tid_address(_tidptr: *mut u32) {
+    Ok(tasking::get_current_task().pid as u32)
+})
+
+// Switches the syscall numbering for this task until it runs another
+// program, returning the previous personality
+syscall!(fn syscall_personality(persona: u32) {
+    let task = tasking::get_current_task();
+    let previous = match task.personality {
+        Rost => PER_ROST,
+        Linux => PER_LINUX
+    };
+
+    match persona {
+        PER_QUERY => {},
+        PER_ROST => task.personality = Rost,
+        PER_LINUX => task.personality = Linux,
+        _ => return Err(EINVAL)
+    }
+    Ok(previous)
+})
+
+syscall!(fn syscall_fork() {
+    Ok(tasking::fork() as u32)
+})
+
+syscall!(fn syscall_waitpid(pid: i32, status: *mut u32, options: u32) {
+    wait(pid, status, options)
+})
+
+// What musl's waitpid() and wait() use. No resource usage is tracked, the
+// whole struct rusage reads as zero.
+syscall!(fn syscall_wait4(pid: i32, status: *mut u32, options: u32, rusage: u32) {
+    let child = try!(wait(pid, status, options));
+    if child != 0 && rusage != 0 {
+        let zero = [0u8, ..RUSAGE_SIZE];
+        try!(copy_to_user(rusage, zero.as_ptr(), zero.len()));
+    }
+    Ok(child)
+})
+
+fn wait(pid: i32, status: *mut u32, options: u32) -> Result<u32, Errno> {
+    let (child, exit_status) = try!(tasking::waitpid(pid as int, options & WNOHANG != 0));
+    if child != 0 && !status.is_null() {
+        try!(write_user(status, exit_status));
+    }
+    Ok(child as u32)
+}
+
+syscall!(fn syscall_execve(path: *const u8, argv: *const *const u8, envp: *const *const u8) {
+    // Everything lives in the address space we're about to tear down
+    let mut buffer = [0u8, ..PATH_MAX];
+    let len = try!(strncpy_from_user(&mut buffer, path as u32));
+    if len == buffer.len() {
+        return Err(ENAMETOOLONG);
+    }
 
-    let mut i = 0;
-    while i < len {
-        let c = unsafe { *data.offset(i as int) as char };
-        AnsiConsole.print(c);
+    let args = try!(Arguments::from_user(argv, envp));
 
-        i += 1;
+    // Only returns if the program could not be started
+    match str::from_utf8(buffer.slice_to(len)) {
+        Some(path) => Err(tasking::execve(path, args)),
+        None => Err(ENOENT)
     }
-    i
 })
 
-syscall!(fn syscall_fork() -> u32 {
-    tasking::fork()
+syscall!(fn syscall_sleep(ms: u32) {
+    timer::sleep(ms);
+    Ok(0)
 })
 
-syscall!(fn syscall_sleep(duration: u32) {
-    // FIXME: We can't interrupt here so do a pseudo-sleep
-    let mut i = 0;
-    while i < duration * 1000000 {
-        unsafe { asm!("nop" :::: "volatile"); }
-        i += 1;
+#[allow(dead_code)]
+#[packed]
+struct Timespec {
+    tv_sec: i32,
+    tv_nsec: i32
+}
+
+syscall!(fn syscall_nanosleep(req: *const Timespec, _rem: *mut Timespec) {
+    let req = try!(read_user(req));
+    let (sec, nsec) = (req.tv_sec, req.tv_nsec);
+    if sec < 0 || nsec < 0 || nsec > 999999999 {
+        return Err(EINVAL);
     }
-    tasking::schedule();
+
+    // Sleep with millisecond resolution, rounding up
+    let ms = if sec as u32 >= u32::MAX / 1000 {
+        u32::MAX
+    } else {
+        sec as u32 * 1000 + (nsec as u32 + 999999) / 1000000
+    };
+
+    // Nothing can interrupt us so there's never any time remaining
+    timer::sleep(ms);
+    Ok(0)
+})
+
+// Linux's struct user_desc, only the base address is used
+#[allow(dead_code)]
+#[packed]
+struct UserDesc {
+    entry_number: u32,
+    base_addr: u32,
+    limit: u32,
+    flags: u32
+}
+
+syscall!(fn syscall_set_thread_area(desc: *mut UserDesc) {
+    let mut user_desc = try!(read_user(desc as *const UserDesc));
+
+    // There's only one TLS entry, -1 asks us to pick one
+    let entry = user_desc.entry_number;
+    if entry != -1i32 as u32 && entry != gdt::TLS_ENTRY {
+        return Err(EINVAL);
+    }
+
+    user_desc.entry_number = gdt::TLS_ENTRY;
+    try!(write_user(desc, user_desc));
+    tasking::set_tls(user_desc.base_addr);
+    Ok(0)
+})
+
+syscall!(fn syscall_brk(addr: u32) {
+    Ok(tasking::get_current_task().mm.brk(addr))
+})
+
+// Only anonymous memory can be mapped, fd and offset are ignored
+syscall!(fn syscall_mmap(addr: u32, len: u32, prot: u32, flags: u32, _fd: i32, _offset: u32) {
+    tasking::get_current_task().mm.mmap(addr, len, prot, flags)
+})
+
+syscall!(fn syscall_munmap(addr: u32, len: u32) {
+    try!(tasking::get_current_task().mm.munmap(addr, len));
+    Ok(0)
+})
+
+syscall!(fn syscall_mprotect(addr: u32, len: u32, prot: u32) {
+    try!(tasking::get_current_task().mm.mprotect(addr, len, prot));
+    Ok(0)
 })
@@ -1,56 +1,101 @@
 use core::prelude::*;
 
-use core::mem::{transmute, size_of};
-use core::ptr::copy_nonoverlapping_memory;
+use core::mem::transmute;
+use core::ptr::{copy_nonoverlapping_memory, set_memory};
 
 use util::Unique;
 use util::list::{List, Node, Rawlink};
 
-use arch::{gdt, idt};
+use arch::{gdt, idt, irq};
 use memory;
+use memory::kernel_stack;
+use memory::kernel_stack::KernelStack;
+use exec::{elf, programs, signal};
+use exec::args::Arguments;
+use exec::mm::MemoryMap;
+use exec::file::FileTable;
+use exec::syscalls::Personality;
+use exec::errno::{Errno, ECHILD, ENOENT};
+
+#[deriving(PartialEq)]
+pub enum State {
+    Running,
+    Ready,
+    Blocked,
+    Zombie
+}
 
-pub type KernelStack = [u8, ..STACK_SIZE];
 pub struct Task {
     pub pid: uint,
+    pub ppid: uint,
+    pub state: State,
+    pub exit_status: u32,
     pub esp: u32,
     pub eip: u32,
     pub pd: u32,
     pub regs: *mut idt::Registers,
+    pub tls: u32, // Base of the TLS segment
+    pub mm: MemoryMap,
+    pub files: FileTable,
+    pub personality: Personality,
     pub kernel_stack: KernelStack
 }
 
-static STACK_SIZE: uint = 8 * 1024;
+// Orphans are handed to this process, if it goes away they end up with the
+// idle task which reaps them
+static INIT_PID: uint = 1;
+
+// Number of timer ticks a user task gets to run before it is preempted, 50ms
+static DEFAULT_QUANTUM: u32 = 50;
+
+static mut quantum: u32 = DEFAULT_QUANTUM;
+static mut slice_left: u32 = DEFAULT_QUANTUM;
 
 static mut next_pid: uint = 1;
 static mut tasks: List<Unique<Task>> = List { head: None, tail: Rawlink { p: 0 as *mut Node<Unique<Task>> }, length: 0 };
+// Tasks that have exited but haven't been reaped by their parent yet
+static mut zombies: List<Unique<Task>> = List { head: None, tail: Rawlink { p: 0 as *mut Node<Unique<Task>> }, length: 0 };
+// Tasks blocked in waitpid()
+static mut waiting: List<Unique<Task>> = List { head: None, tail: Rawlink { p: 0 as *mut Node<Unique<Task>> }, length: 0 };
+// Every task regardless of which queue currently owns it
+static mut all_tasks: List<*mut Task> = List { head: None, tail: Rawlink { p: 0 as *mut Node<*mut Task> }, length: 0 };
 
 pub static mut current_task: Option<Unique<Task>> = None;
 
 impl Task {
     pub fn stack_top(&self) -> u32 {
-        let stack_bottom: u32 = unsafe { transmute(&self.kernel_stack) };
-        stack_bottom + size_of::<KernelStack>() as u32
+        self.kernel_stack.top
+    }
+
+    /// The trap frame saved when the task last entered the kernel from user
+    /// mode, always right at the top of its kernel stack.
+    pub fn user_regs<'a>(&'a self) -> &'a idt::Registers {
+        unsafe { &*(self.stack_top() as *const idt::Registers).offset(-1) }
     }
 }
 
-// We can't use the Unique::new() constructor becase it
-// creates the Task on the stack and then copies it into
-// the memory. This fails because this task is actually
-// larger than the stack we're operating on.
+// The task is set up in place rather than through Unique::new(), anything
+// not given here starts out zeroed.
 macro_rules! new_task (
     (Task {
         pid: $pid:expr,
+        ppid: $ppid:expr,
         esp: $esp:expr,
         eip: $eip:expr,
         pd: $pd:expr,
-        regs: $regs:expr
+        regs: $regs:expr,
+        kernel_stack: $kernel_stack:expr
 
     }) => ({
         let mut task: Unique<Task> = Unique::empty();
         task.pid = $pid;
+        task.ppid = $ppid;
+        task.state = Ready;
         task.eip = $eip;
         task.pd = $pd;
         task.regs = $regs;
+        task.kernel_stack = $kernel_stack;
+        all_tasks.append(task.deref_mut() as *mut Task);
         task
     })
 )
@@ -59,13 +104,18 @@ pub fn init() {
     unsafe {
         let mut task = new_task!(Task {
             pid: 0,
+            ppid: 0,
             esp: 0,
             eip: 0,
             pd: memory::kernel_directory,
-            regs: 0 as *mut idt::Registers
+            regs: 0 as *mut idt::Registers,
+            // The idle task carries on with the stack it booted on
+            kernel_stack: kernel_stack::boot_stack()
         });
 
+        task.state = Running;
         gdt::set_kernel_stack(task.stack_top());
+        gdt::set_stack_limit(task.kernel_stack.limit);
 
         current_task = Some(task);
     }
@@ -78,50 +128,211 @@ pub fn get_current_task() -> &mut Unique<Task> {
     }
 }
 
-pub fn kill() {
+/// Terminates the current task with the given exit code.
+pub fn exit(code: u32) -> ! {
+    terminate((code & 0xff) << 8)
+}
+
+/// Terminates the current task as if it had been killed by the signal.
+pub fn kill(signal: u32) -> ! {
+    terminate(signal & 0x7f)
+}
+
+// Turns the current task into a zombie carrying a waitpid() status, its
+// resources are reclaimed once the parent has collected the status
+fn terminate(status: u32) -> ! {
     unsafe {
-        if get_current_task().pid == 0 {
+        irq::disable_interrupts();
+
+        let mut task = match current_task.take() {
+            None => panic!("No current task, is tasking initialized?"),
+            Some(task) => task
+        };
+
+        if task.pid == 0 {
             panic!("Can not kill idle task");
         }
 
+        // Nothing in user space is needed anymore, the directory itself
+        // goes once the parent has collected us
+        memory::clear_user_space();
+        task.mm.clear();
+        task.files.close_all();
+
+        task.state = Zombie;
+        task.exit_status = status;
+
+        reparent_children(task.pid);
+
+        let ppid = task.ppid;
+        let prev = task.deref_mut() as *mut Task;
+        zombies.append(task);
+        wake_waiter(ppid);
+
         current_task = tasks.pop_front();
-        replace_current(get_current_task());
+        switch_to(&mut *prev, get_current_task().deref());
+        unreachable!();
     }
 }
 
+unsafe fn reparent_children(pid: uint) {
+    // Pids aren't reused, once init has exited it's gone for good
+    let init_alive = all_tasks.iter().any(|&task| (*task).pid == INIT_PID && (*task).state != Zombie);
+    let new_parent = if pid != INIT_PID && init_alive { INIT_PID } else { 0 };
+
+    let mut zombie_children = false;
+    for &task in all_tasks.iter() {
+        if (*task).ppid == pid {
+            (*task).ppid = new_parent;
+            zombie_children |= (*task).state == Zombie;
+        }
+    }
+
+    if zombie_children {
+        wake_waiter(new_parent);
+    }
+}
+
+// Wakes the task up if it's blocked in waitpid()
+unsafe fn wake_waiter(pid: uint) {
+    match waiting.remove_where(|task| task.pid == pid) {
+        None => {},
+        Some(task) => wake(task)
+    }
+}
+
+/// Waits for a child to exit and reaps it. A pid of zero or less matches
+/// any child as there are no process groups. Returns the pid and exit
+/// status of the child, or a pid of zero if nohang is set and no child has
+/// exited yet.
+pub fn waitpid(pid: int, nohang: bool) -> Result<(uint, u32), Errno> {
+    loop {
+        unsafe {
+            let parent = get_current_task().pid;
+
+            match zombies.remove_where(|task| is_child(task.deref(), parent, pid)) {
+                None => {},
+                Some(zombie) => {
+                    let result = (zombie.pid, zombie.exit_status);
+                    reap(zombie);
+                    return Ok(result);
+                }
+            }
+
+            if !all_tasks.iter().any(|&task| is_child(&*task, parent, pid)) {
+                return Err(ECHILD);
+            }
+
+            if nohang {
+                return Ok((0, 0));
+            }
+
+            block(|task| waiting.append(task));
+        }
+    }
+}
+
+fn is_child(task: &Task, parent: uint, pid: int) -> bool {
+    task.ppid == parent && (pid <= 0 || task.pid == pid as uint)
+}
+
+/// Reaps every child of the current task that has exited, without blocking.
+pub fn reap_children() {
+    loop {
+        match waitpid(-1, true) {
+            Ok((0, _)) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+}
+
+// Frees everything still held by a zombie
+unsafe fn reap(mut task: Unique<Task>) {
+    let ptr = task.deref_mut() as *mut Task;
+    all_tasks.remove_where(|&other| other == ptr);
+
+    memory::free_directory(task.pd);
+    kernel_stack::free(task.kernel_stack);
+    task.drop();
+}
+
 pub fn exec(f: fn()) {
-    let mut new_task = new_task!(Task {
-        pid: aquire_pid(),
-        esp: 0,
-        eip: unsafe { transmute(f) },
-        pd: memory::clone_directory(),
-        regs: 0 as *mut idt::Registers // FIXME  
-    });
+    unsafe {
+        let mut new_task = new_task!(Task {
+            pid: aquire_pid(),
+            ppid: get_current_task().pid,
+            esp: 0,
+            eip: 0,
+            pd: memory::clone_directory(),
+            regs: 0 as *mut idt::Registers,
+            kernel_stack: kernel_stack::allocate()
+        });
+
+        // Start out by "returning" from a trap into f in kernel mode, that
+        // way the task starts with interrupts enabled like any other
+        let regs = push_trap_frame(&mut new_task);
+        (*regs).eip = transmute(f);
+        (*regs).cs = 0x08;
+        (*regs).eflags = read_eflags() | 0x200;
+        (*regs).ds = 0x10;
+        (*regs).es = 0x10;
+        (*regs).fs = 0x10;
+        (*regs).gs = gdt::KERNEL_GS as u32;
+
+        // Whatever it goes on to run starts out talking to the console
+        new_task.files.open_console();
+
+        tasks.append(new_task);
+    }
+}
+
+/// Replaces the image of the current task with the program at path, only
+/// returns if the program could not be started.
+pub fn execve(path: &str, args: Arguments) -> Errno {
+    let (file, personality) = match programs::lookup(path) {
+        Some(program) => program,
+        None => return ENOENT
+    };
+
+    match elf::validate(file) {
+        Err(e) => {
+            kprintln!("Can not execute {}: {}", path, e.description());
+            return e.errno();
+        },
+        Ok(()) => {}
+    }
 
-    new_task.esp = new_task.stack_top();
+    // There's no going back once the old image is gone
+    memory::clear_user_space();
+    get_current_task().mm.clear();
+    get_current_task().personality = personality;
+    let error = elf::exec(file, args);
 
-    unsafe { tasks.append(new_task); }
+    kprintln!("Process {} killed: failed to load {}: {}", get_current_task().pid, path, error.description());
+    kill(signal::SIGKILL)
 }
 
 pub fn fork() -> uint {
     unsafe {
-        extern { static ret_from_trap: u32; }
-
         let mut new_task = new_task!(Task {
             pid: aquire_pid(),
+            ppid: get_current_task().pid,
             esp: 0,
-            eip: transmute(&ret_from_trap),
+            eip: 0,
             pd: memory::clone_directory(),
-            regs: 0 as *mut idt::Registers      
+            regs: 0 as *mut idt::Registers,
+            kernel_stack: kernel_stack::allocate()
         });
 
-        let regs_end = new_task.stack_top() as *mut idt::Registers;
-        let regs = regs_end.offset(-1);
+        let regs = push_trap_frame(&mut new_task);
         copy_nonoverlapping_memory(regs, get_current_task().regs as *const idt::Registers, 1);
-
-        new_task.esp = regs as u32;
         (*regs).eax = 0;
 
+        new_task.tls = get_current_task().tls;
+        new_task.mm = get_current_task().mm.clone();
+        new_task.files = get_current_task().files.clone();
+        new_task.personality = get_current_task().personality;
+
         let child_pid = new_task.pid;
 
         tasks.append(new_task);
@@ -130,6 +341,19 @@ pub fn fork() -> uint {
     }
 }
 
+// Reserves room for a trap frame at the top of the task's kernel stack and
+// makes the task start by returning from it through ret_from_trap
+unsafe fn push_trap_frame(task: &mut Unique<Task>) -> *mut idt::Registers {
+    extern { static ret_from_trap: u32; }
+
+    let regs = (task.stack_top() as *mut idt::Registers).offset(-1);
+    set_memory(regs, 0, 1);
+
+    task.esp = regs as u32;
+    task.eip = transmute(&ret_from_trap);
+    regs
+}
+
 fn read_eflags() -> u32 {
     unsafe {
         let mut eflags: u32;
@@ -157,61 +381,133 @@ pub fn user_mode(entry: u32, stack: u32) {
         eip: entry
     };
 
-    gdt::set_segments(0x20 | 0x3);
-    extern { fn run_iret(stack: FakeStack); }
-    unsafe { run_iret(fake_stack); }
+    extern { fn run_iret(gs: u32, stack: FakeStack); }
+    unsafe { run_iret(gdt::TLS_SELECTOR as u32, fake_stack); }
+}
+
+/// Points the TLS segment of the current task at base.
+pub fn set_tls(base: u32) {
+    get_current_task().tls = base;
+    gdt::set_tls(base);
+}
+
+/// Sets the number of timer ticks a user task may run before it is preempted.
+#[allow(dead_code)]
+pub fn set_quantum(ticks: u32) {
+    kassert!(ticks > 0);
+    unsafe { quantum = ticks; }
+}
+
+/// Called on every timer interrupt, switches away from the current task
+/// once it has used up its time slice.
+pub fn tick(regs: &idt::Registers) {
+    unsafe {
+        if slice_left > 0 {
+            slice_left -= 1;
+        }
+
+        // The kernel itself is not preemptible, wait until we interrupt user mode
+        if slice_left == 0 && regs.from_user() {
+            schedule();
+        }
+    }
 }
 
 pub fn schedule() {
     unsafe {
+        // Interrupt handlers may wake tasks up, keep them off the run queue
+        // while we're modifying it
+        let eflags = irq::disable_interrupts();
+        slice_left = quantum;
+
         let task = match tasks.pop_front() {
-            None => return,
+            None => return irq::restore_interrupts(eflags),
             Some(task) => task
         };
 
         let (last_task, next_task) = match current_task.take() {
             None => panic!("No current task, is tasking initialized?"),
-            Some(current) => {
+            Some(mut current) => {
+                current.state = Ready;
                 tasks.append(current);
                 current_task = Some(task);
-                (tasks.back_mut().unwrap(), current_task.get_ref())
+                (tasks.back_mut().unwrap(), current_task.get_mut_ref())
             }
         };
 
-        switch_to(last_task, next_task);
+        next_task.state = Running;
+
+        switch_to(last_task.deref_mut(), next_task.deref());
+        irq::restore_interrupts(eflags);
     }
 }
 
+/// Takes the current task off the run queue and hands it over to f, which
+/// is responsible for passing it to wake() later on. Returns once woken up.
+pub fn block(f: |Unique<Task>|) {
+    unsafe {
+        let eflags = irq::disable_interrupts();
+        slice_left = quantum;
+
+        let mut current = match current_task.take() {
+            None => panic!("No current task, is tasking initialized?"),
+            Some(current) => current
+        };
+
+        if current.pid == 0 {
+            panic!("Can not block idle task");
+        }
+
+        // The task itself stays put in memory even when the handle moves
+        current.state = Blocked;
+        let prev = current.deref_mut() as *mut Task;
+        f(current);
+
+        // The idle task never blocks so there is always something to run
+        current_task = tasks.pop_front();
+        get_current_task().state = Running;
+        switch_to(&mut *prev, get_current_task().deref());
+        irq::restore_interrupts(eflags);
+    }
+}
+
+/// Puts a task previously handed out by block() back on the run queue.
+pub fn wake(mut task: Unique<Task>) {
+    let eflags = irq::disable_interrupts();
+    task.state = Ready;
+    unsafe { tasks.append(task); }
+    irq::restore_interrupts(eflags);
+}
+
 #[inline(never)] // We can't inline because then the label "resume" would fail to be found
-unsafe fn switch_to(prev: &mut Unique<Task>, next: &Unique<Task>) {
+unsafe fn switch_to(prev: &mut Task, next: &Task) {
     // These blocks are split in two because we need to guarantee that the store
     // into prev.esp and prev.eip happens BEFORE the jmp. Optimally we would like
     // to use "=m" as a constraint but rustc/llvm doesn't seem to like that.
-    // Without the explicit deref_mut() the values are borrowed as immutable.
+    // The interrupt flag is saved along with the rest so that a task switched
+    // out from an interrupt handler resumes with interrupts still disabled.
     asm!(
-        "cli;
+        "pushf;
+        cli;
         push %ebp;
         mov %esp, $0;
         lea resume, $1;"
-        : "=r"(prev.deref_mut().esp), "=r"(prev.deref_mut().eip) ::: "volatile");
+        : "=r"(prev.esp), "=r"(prev.eip) ::: "volatile");
 
     gdt::set_kernel_stack(next.stack_top());
+    gdt::set_tls(next.tls);
     memory::switch_page_directory(next.pd);
 
+    // The stack limit checked by function prologues has to change along
+    // with the stack itself, see gdt::CpuArea
     asm!(
-       "mov $0, %esp;
-       sti;
+       "mov $2, %gs:0x30;
+       mov $0, %esp;
        jmp *$1;
        resume:
-       pop %ebp;"
-       :: "m"(next.esp), "m"(next.eip) :: "volatile");
-}
-
-unsafe fn replace_current(next: &Unique<Task>) {
-    asm!(
-       "mov $0, %esp;
-       jmp *$1;"
-       :: "m"(next.esp), "m"(next.eip) :: "volatile");
+       pop %ebp;
+       popf;"
+       :: "m"(next.esp), "m"(next.eip), "r"(next.kernel_stack.limit) :: "volatile");
 }
 
 fn aquire_pid() -> uint {
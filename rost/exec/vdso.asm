@@ -0,0 +1,45 @@
+; The vDSO is a single page mapped read-only into every process, holding
+; __kernel_vsyscall at its very start. Programs find it through AT_SYSINFO
+; and call it with the registers set up as for int 0x80. Which of the two
+; versions below ends up in the page depends on the CPU, see exec::vdso.
+; Both only use relative addressing, they run at VDSO_ADDRESS.
+
+section .rodata
+
+; sysenter doesn't save the user eip or esp. The kernel always returns to
+; vdso_sysenter_return and takes the user stack from ebp, the sixth argument
+; ebp would otherwise carry is read from the top of that stack.
+global vdso_sysenter_start
+vdso_sysenter_start:
+    push ecx
+    push edx
+    push ebp
+    mov ebp, esp
+    sysenter
+
+    ; sysexit overwrites ecx and edx
+global vdso_sysenter_return
+vdso_sysenter_return:
+    pop ebp
+    pop edx
+    pop ecx
+    ret
+
+global vdso_sysenter_end
+vdso_sysenter_end:
+
+global vdso_int80_start
+vdso_int80_start:
+    int 0x80
+    ret
+
+global vdso_int80_end
+vdso_int80_end:
+
+section .bss
+
+; The page itself, part of the kernel image and filled in at boot
+align 4096
+global vdso_page
+vdso_page:
+    resb 4096
@@ -0,0 +1,75 @@
+use core::prelude::*;
+use core::mem::transmute;
+use core::ptr::copy_nonoverlapping_memory;
+
+use arch::{cpu, gdt};
+use memory;
+
+/// Where the vDSO is mapped in every process, the page right above the
+/// stack. Passed to programs as AT_SYSINFO.
+pub static VDSO_ADDRESS: u32 = memory::STACK_TOP;
+
+static SYSENTER_CS: u32 = 0x174;
+static SYSENTER_ESP: u32 = 0x175;
+static SYSENTER_EIP: u32 = 0x176;
+
+// cpuid leaf 1, edx
+static CPUID_SEP: u32 = 1 << 11;
+
+extern {
+    static vdso_page: u8;
+    static vdso_sysenter_start: u8;
+    static vdso_sysenter_return: u8;
+    static vdso_sysenter_end: u8;
+    static vdso_int80_start: u8;
+    static vdso_int80_end: u8;
+
+    fn sysenter_entry();
+    static mut sysenter_stack_top: u32;
+}
+
+/// The user address sysenter_entry returns to.
+#[no_mangle]
+pub static mut sysenter_return: u32 = 0;
+
+/// Fills in the vDSO with the fastest way into the kernel the CPU supports,
+/// setting up sysenter if that's it.
+pub fn init() {
+    unsafe {
+        let (start, end) = if has_sysenter() {
+            // The user segments follow the kernel ones, sysexit relies on that
+            cpu::write_msr(SYSENTER_CS, 0x08);
+            // sysenter_entry finds the kernel stack through the top of its own
+            sysenter_stack_top = gdt::kernel_stack_pointer();
+            cpu::write_msr(SYSENTER_ESP, transmute::<_, u32>(&sysenter_stack_top) as u64);
+            cpu::write_msr(SYSENTER_EIP, transmute::<_, u32>(sysenter_entry) as u64);
+
+            sysenter_return = VDSO_ADDRESS + address(&vdso_sysenter_return) - address(&vdso_sysenter_start);
+            (address(&vdso_sysenter_start), address(&vdso_sysenter_end))
+        } else {
+            (address(&vdso_int80_start), address(&vdso_int80_end))
+        };
+
+        copy_nonoverlapping_memory(address(&vdso_page) as *mut u8, start as *const u8, (end - start) as uint);
+    }
+}
+
+/// Maps the vDSO into the current address space.
+pub fn map() {
+    let frame = address(&vdso_page) - memory::KERNEL_BASE;
+    memory::map_frame(VDSO_ADDRESS, frame, memory::USER | memory::EXEC);
+}
+
+fn has_sysenter() -> bool {
+    let (signature, _, _, features) = cpu::cpuid(1);
+    let family = (signature >> 8) & 0xf;
+    let model = (signature >> 4) & 0xf;
+    let stepping = signature & 0xf;
+
+    // Early Pentium Pros claim support without having it
+    features & CPUID_SEP != 0 && !(family == 6 && model < 3 && stepping < 3)
+}
+
+fn address(symbol: &u8) -> u32 {
+    unsafe { transmute(symbol) }
+}
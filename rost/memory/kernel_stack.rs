@@ -0,0 +1,82 @@
+use core::prelude::*;
+
+use memory;
+
+static PAGE_SIZE: u32 = 0x1000;
+
+// Every kernel stack gets a slot in this region, with the stack at the top
+// of the slot and an unmapped guard page below it
+static STACKS_START: u32 = 0xE0000000;
+static STACK_SIZE: u32 = 8 * 1024;
+static SLOT_SIZE: u32 = STACK_SIZE + PAGE_SIZE;
+static MAX_STACKS: uint = 1024;
+
+// Function prologues call __morestack once the stack would go below the
+// limit, leave enough room above the guard page to report it from there
+static RED_ZONE: u32 = 2 * 1024;
+
+// A set bit means the slot is in use
+static mut slots: [u32, ..MAX_STACKS / 32] = [0, ..MAX_STACKS / 32];
+
+pub struct KernelStack {
+    pub top: u32,
+    pub limit: u32
+}
+
+/// Maps a new kernel stack in a free slot.
+pub fn allocate() -> KernelStack {
+    unsafe {
+        for i in range(0, MAX_STACKS) {
+            if slots[i / 32] & (1 << (i % 32)) == 0 {
+                slots[i / 32] |= 1 << (i % 32);
+
+                let bottom = STACKS_START + i as u32 * SLOT_SIZE + PAGE_SIZE;
+                memory::map(bottom, STACK_SIZE, memory::WRITE);
+                return KernelStack {
+                    top: bottom + STACK_SIZE,
+                    limit: bottom + RED_ZONE
+                };
+            }
+        }
+
+        panic!("Out of kernel stacks");
+    }
+}
+
+/// Unmaps a stack returned by allocate(), it must not be in use anymore.
+pub fn free(stack: KernelStack) {
+    let bottom = stack.top - STACK_SIZE;
+    kassert!(bottom >= STACKS_START && (bottom - STACKS_START) % SLOT_SIZE == PAGE_SIZE);
+
+    memory::unmap(bottom, STACK_SIZE);
+
+    let i = ((bottom - STACKS_START) / SLOT_SIZE) as uint;
+    unsafe {
+        slots[i / 32] &= !(1 << (i % 32));
+    }
+}
+
+/// The stack set up by boot.asm, there's no guard page below it.
+pub fn boot_stack() -> KernelStack {
+    extern {
+        static stack_bottom: u8;
+        static stack_top: u8;
+    }
+
+    KernelStack {
+        top: &stack_top as *const u8 as u32,
+        limit: &stack_bottom as *const u8 as u32 + RED_ZONE
+    }
+}
+
+/// Is the address inside the guard page of any kernel stack.
+pub fn is_guard(addr: u32) -> bool {
+    addr >= STACKS_START && addr < STACKS_START + MAX_STACKS as u32 * SLOT_SIZE
+        && (addr - STACKS_START) % SLOT_SIZE < PAGE_SIZE
+}
+
+// Called by __morestack in runtime.asm, which has already lifted the limit
+#[no_mangle]
+pub extern fn kernel_stack_overflow() -> ! {
+    panic!("Kernel stack overflow");
+}
@@ -1,35 +1,207 @@
+use core::prelude::*;
+use core::mem::size_of;
 use core::ptr::copy_nonoverlapping_memory;
 use libc::{size_t, c_void};
 
+use arch::irq;
 use memory;
 
-static PAGE_SIZE: u32 = 0x1000;
+static PAGE_SIZE: uint = 0x1000;
 
-static mut heap: u32 = 0xd0000000;
-static mut buffer: u32 = 0;
+static HEAP_START: uint = 0xd0000000;
+static HEAP_LIMIT: uint = 0xe0000000;
+
+// Every allocation is prefixed by a block header. Free blocks are kept in
+// a list sorted by address so that neighbours can be merged when freed.
+struct Block {
+    size: uint, // Size of the whole block, including this header
+    next: *mut Block
+}
+
+// Marks a block as handed out, helps catch double and invalid frees
+static ALLOCATED: *mut Block = 1 as *mut Block;
+
+static HEADER_SIZE: uint = 8;
+static MIN_ALIGN: uint = 8;
+static MIN_BLOCK: uint = 16;
+
+static mut free_list: *mut Block = 0 as *mut Block;
+static mut heap_end: uint = HEAP_START;
 
 pub unsafe fn malloc(size: size_t) -> *mut c_void {
-    klog!("Allocating {} bytes", size);
+    kassert!(size_of::<Block>() == HEADER_SIZE);
 
-    while buffer < size {
-        memory::map(heap + buffer, PAGE_SIZE, memory::WRITE);
-        buffer += PAGE_SIZE;
-    }
+    // The heap is used from interrupt handlers as well
+    let eflags = irq::disable_interrupts();
+    let block = take_block(block_size(size as uint));
+    irq::restore_interrupts(eflags);
 
-    let ptr = heap;
-    heap += size;
-    buffer -= size;
+    if block.is_null() {
+        return 0 as *mut c_void;
+    }
 
-    ptr as *mut c_void
+    payload(block)
 }
 
 pub unsafe fn realloc(p: *mut c_void, size: size_t) -> *mut c_void {
+    if p.is_null() {
+        return malloc(size);
+    }
+
+    if size == 0 {
+        free(p);
+        return 0 as *mut c_void;
+    }
+
+    let available = (*header(p)).size - HEADER_SIZE;
+    if available >= size as uint {
+        return p;
+    }
+
     let ptr = malloc(size);
-    copy_nonoverlapping_memory(ptr, p as *const c_void, size as uint);
-    free(p);
+    if !ptr.is_null() {
+        copy_nonoverlapping_memory(ptr as *mut u8, p as *const u8, available);
+        free(p);
+    }
     ptr
 }
 
-pub unsafe fn free(_: *mut c_void) {
-    // Do nothing :(
+pub unsafe fn free(p: *mut c_void) {
+    if p.is_null() {
+        return;
+    }
+
+    let block = header(p);
+    kassert!((*block).next == ALLOCATED);
+
+    let eflags = irq::disable_interrupts();
+    release(block);
+    irq::restore_interrupts(eflags);
+}
+
+pub unsafe fn memalign(align: uint, size: size_t) -> *mut c_void {
+    if align <= MIN_ALIGN {
+        return malloc(size);
+    }
+
+    // Allocate enough to be able to slide the block forward to the
+    // alignment, the skipped space must be big enough to be freed again
+    let p = malloc((size as uint + align + MIN_BLOCK) as size_t);
+    if p.is_null() {
+        return p;
+    }
+
+    let start = p as uint;
+    let mut aligned = align_up(start, align);
+    if aligned == start {
+        return p;
+    }
+    if aligned - start < MIN_BLOCK {
+        aligned += align;
+    }
+
+    let block = header(p);
+    let aligned_block = header(aligned as *mut c_void);
+    (*aligned_block).size = (*block).size - (aligned - start);
+    (*aligned_block).next = ALLOCATED;
+
+    (*block).size = aligned - start;
+
+    let eflags = irq::disable_interrupts();
+    release(block);
+    irq::restore_interrupts(eflags);
+
+    aligned as *mut c_void
+}
+
+unsafe fn take_block(size: uint) -> *mut Block {
+    loop {
+        let mut prev: *mut *mut Block = &mut free_list;
+        let mut block = free_list;
+
+        while !block.is_null() {
+            if (*block).size >= size {
+                if (*block).size - size >= MIN_BLOCK {
+                    // Split off the tail, it takes our place in the free list
+                    let rest = (block as uint + size) as *mut Block;
+                    (*rest).size = (*block).size - size;
+                    (*rest).next = (*block).next;
+                    (*block).size = size;
+                    *prev = rest;
+                } else {
+                    *prev = (*block).next;
+                }
+
+                (*block).next = ALLOCATED;
+                return block;
+            }
+
+            prev = &mut (*block).next;
+            block = (*block).next;
+        }
+
+        if !grow(size) {
+            return 0 as *mut Block;
+        }
+    }
+}
+
+unsafe fn grow(size: uint) -> bool {
+    let size = align_up(size, PAGE_SIZE);
+    if heap_end + size > HEAP_LIMIT {
+        klog!("Kernel heap exhausted, failed to grow by {} bytes", size);
+        return false;
+    }
+
+    memory::map(heap_end as u32, size as u32, memory::WRITE);
+
+    let block = heap_end as *mut Block;
+    (*block).size = size;
+    heap_end += size;
+
+    release(block);
+    true
+}
+
+// Put a block back in the free list, merging it with adjacent free blocks
+unsafe fn release(block: *mut Block) {
+    let mut prev = 0 as *mut Block;
+    let mut next = free_list;
+    while !next.is_null() && (next as uint) < (block as uint) {
+        prev = next;
+        next = (*next).next;
+    }
+
+    if !next.is_null() && block as uint + (*block).size == next as uint {
+        (*block).size += (*next).size;
+        (*block).next = (*next).next;
+    } else {
+        (*block).next = next;
+    }
+
+    if prev.is_null() {
+        free_list = block;
+    } else if prev as uint + (*prev).size == block as uint {
+        (*prev).size += (*block).size;
+        (*prev).next = (*block).next;
+    } else {
+        (*prev).next = block;
+    }
+}
+
+fn block_size(size: uint) -> uint {
+    let size = align_up(size + HEADER_SIZE, MIN_ALIGN);
+    if size < MIN_BLOCK { MIN_BLOCK } else { size }
+}
+
+fn header(p: *mut c_void) -> *mut Block {
+    (p as uint - HEADER_SIZE) as *mut Block
+}
+
+fn payload(block: *mut Block) -> *mut c_void {
+    (block as uint + HEADER_SIZE) as *mut c_void
+}
+
+fn align_up(value: uint, align: uint) -> uint {
+    (value + align - 1) & !(align - 1)
 }
@@ -1,7 +1,19 @@
+use arch::multiboot;
+
 pub use self::virt::{
     kernel_directory,
+    USER_SPACE,
+    KERNEL_SPACE,
+    KERNEL_BASE,
+    STACK_TOP,
+    STACK_GUARD,
     map,
+    map_frame,
+    unmap,
+    protect,
     clone_directory,
+    clear_user_space,
+    free_directory,
     switch_page_directory,
     Flags,
     NONE,
@@ -13,8 +25,10 @@ pub use self::virt::{
 mod physical;
 mod virt;
 pub mod malloc;
+pub mod kernel_stack;
+pub mod user;
 
-pub fn init() {
-    physical::init();
+pub fn init(info: &multiboot::Info) {
+    physical::init(info);
     virt::init();
 }
@@ -1,22 +1,206 @@
+use core::prelude::*;
+use core::mem::size_of;
+use core::ptr::zero_memory;
+use libc::size_t;
+
+use arch::multiboot;
+use memory::KERNEL_BASE;
+use memory::malloc::malloc;
+
 static FRAME_SIZE: u32 = 0x1000;
+static FRAME_SHIFT: uint = 12;
+
+// One bit per frame, enough to cover the whole 4GB physical address space
+static MAX_FRAMES: uint = 1024 * 1024;
+static BITMAP_SIZE: uint = MAX_FRAMES / 32;
+
+// A set bit means the frame is free, that way the bitmap lives in .bss and
+// everything starts out as used until the memory map tells us otherwise
+static mut bitmap: [u32, ..BITMAP_SIZE] = [0, ..BITMAP_SIZE];
+
+// One past the highest frame we have seen in the memory map
+static mut frame_limit: uint = 0;
+// No free frames exist in the bitmap before this word
+static mut first_free: uint = 0;
+static mut free_count: uint = 0;
+
+// Number of references to a frame on top of the one taken by allocating
+// it. Only shared frames need this so it's allocated the first time a
+// frame is shared, when the heap is up and running.
+static mut refcounts: *mut u16 = 0 as *mut u16;
+
+pub fn init(info: &multiboot::Info) {
+    if info.has_memory_map() {
+        info.each_region(|region| {
+            if region.is_available() {
+                release_region(region.base, region.length);
+            }
+        });
+    } else {
+        // No memory map, fall back to assuming everything above 1MB is usable
+        match info.upper_memory() {
+            Some(size) => release_region(0x100000, size as u64),
+            None => panic!("Bootloader did not provide any memory information")
+        }
+    }
 
-static mut next_frame: u32 = 1024 * 4; // First 4MB is in use, TODO: Make this nicer
+    unsafe {
+        // The first MB holds the BIOS data, the VGA buffer and friends
+        // and the kernel image is loaded right after it
+        extern { static kernel_end: u8; }
+        reserve_range(0, &kernel_end as *const u8 as u32 - KERNEL_BASE);
+    }
 
+    // The multiboot structures and modules must stay intact
+    let info_addr = info as *const multiboot::Info as u32 - KERNEL_BASE;
+    reserve_range(info_addr, size_of::<multiboot::Info>() as u32);
+    let (mmap_addr, mmap_length) = info.memory_map_range();
+    reserve_range(mmap_addr, mmap_length);
+    info.each_module(|module| {
+        reserve_range(module.start, module.end - module.start);
+    });
 
-pub fn init() {
-    /*
     unsafe {
-        // Mark frames up to kernel_end as used
-        extern { static kernel_end: u32; }
-        next_frame = kernel_end % FRAME_SIZE + 1;
+        klog!("Physical memory: {} of {} frames free", free_count, frame_limit);
     }
-    */
 }
 
 pub fn allocate_frame() -> u32 {
     unsafe {
-        let frame = next_frame;
-        next_frame += 1;
-        frame * FRAME_SIZE
+        let words = (frame_limit + 31) / 32;
+
+        let mut i = first_free;
+        while i < words {
+            if bitmap[i] != 0 {
+                let bit = lowest_set_bit(bitmap[i]);
+                bitmap[i] &= !(1 << bit);
+
+                first_free = i;
+                free_count -= 1;
+                return ((i * 32 + bit) << FRAME_SHIFT) as u32;
+            }
+            i += 1;
+        }
+
+        panic!("Out of physical memory");
+    }
+}
+
+pub fn free_frame(frame: u32) {
+    kassert!(frame & (FRAME_SIZE - 1) == 0);
+
+    let index = (frame >> FRAME_SHIFT) as uint;
+    kassert!(index < unsafe { frame_limit });
+    kassert!(!is_free(index));
+
+    unsafe {
+        if !refcounts.is_null() && *refcounts.offset(index as int) > 0 {
+            *refcounts.offset(index as int) -= 1;
+            return;
+        }
+    }
+
+    set_free(index);
+}
+
+/// Takes another reference to an allocated frame, it is not returned to
+/// the pool until free_frame() has been called once for every reference.
+pub fn share_frame(frame: u32) {
+    let index = (frame >> FRAME_SHIFT) as uint;
+    kassert!(index < unsafe { frame_limit });
+    kassert!(!is_free(index));
+
+    unsafe {
+        if refcounts.is_null() {
+            refcounts = malloc((frame_limit * size_of::<u16>()) as size_t) as *mut u16;
+            kassert!(!refcounts.is_null());
+            zero_memory(refcounts, frame_limit);
+        }
+
+        *refcounts.offset(index as int) += 1;
+    }
+}
+
+/// Returns true if more than one reference to the frame exists.
+pub fn is_shared(frame: u32) -> bool {
+    let index = (frame >> FRAME_SHIFT) as uint;
+    unsafe {
+        !refcounts.is_null() && *refcounts.offset(index as int) > 0
+    }
+}
+
+#[allow(dead_code)]
+pub fn free_frames() -> uint {
+    unsafe { free_count }
+}
+
+fn release_region(base: u64, length: u64) {
+    // We can't address anything above 4GB
+    let limit = MAX_FRAMES as u64 << FRAME_SHIFT;
+    if base >= limit {
+        return;
+    }
+
+    let end = if base + length > limit { limit } else { base + length };
+
+    // Only frames that are entirely inside the region are usable
+    let first = ((base + FRAME_SIZE as u64 - 1) >> FRAME_SHIFT) as uint;
+    let last = (end >> FRAME_SHIFT) as uint;
+
+    unsafe {
+        if last > frame_limit {
+            frame_limit = last;
+        }
+    }
+
+    for index in range(first, last) {
+        if !is_free(index) {
+            set_free(index);
+        }
+    }
+}
+
+fn reserve_range(addr: u32, size: u32) {
+    if size == 0 {
+        return;
+    }
+
+    let first = (addr >> FRAME_SHIFT) as uint;
+    let last = ((addr as u64 + size as u64 + FRAME_SIZE as u64 - 1) >> FRAME_SHIFT) as uint;
+
+    for index in range(first, last) {
+        if index < unsafe { frame_limit } && is_free(index) {
+            set_used(index);
+        }
+    }
+}
+
+fn is_free(index: uint) -> bool {
+    unsafe { bitmap[index / 32] & (1 << (index % 32)) != 0 }
+}
+
+fn set_free(index: uint) {
+    unsafe {
+        bitmap[index / 32] |= 1 << (index % 32);
+        free_count += 1;
+
+        if index / 32 < first_free {
+            first_free = index / 32;
+        }
+    }
+}
+
+fn set_used(index: uint) {
+    unsafe {
+        bitmap[index / 32] &= !(1 << (index % 32));
+        free_count -= 1;
+    }
+}
+
+fn lowest_set_bit(word: u32) -> uint {
+    let mut bit = 0;
+    while word & (1 << bit) == 0 {
+        bit += 1;
     }
+    bit
 }
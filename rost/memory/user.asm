@@ -0,0 +1,65 @@
+; Accessing user memory may fault. Every instruction that can is listed in
+; __ex_table along with where to continue if the fault can't be resolved,
+; see memory::user.
+
+%macro EX_TABLE 2
+    section __ex_table progbits alloc noexec nowrite align=4
+        dd %1, %2
+    section .text
+%endmacro
+
+section .text
+
+; u32 user_copy(dst, src, len), returns the number of bytes not copied
+global user_copy
+user_copy:
+    push esi
+    push edi
+    mov edi, [esp + 12]
+    mov esi, [esp + 16]
+    mov ecx, [esp + 20]
+
+.copy:
+    ; ecx is left at the number of bytes remaining if this faults
+    rep movsb
+.done:
+    mov eax, ecx
+    pop edi
+    pop esi
+    ret
+
+EX_TABLE user_copy.copy, user_copy.done
+
+; i32 user_strncpy(dst, src, max), returns the length of the string or max
+; if it didn't fit, -1 if it faulted
+global user_strncpy
+user_strncpy:
+    push esi
+    push edi
+    mov edi, [esp + 12]
+    mov esi, [esp + 16]
+    mov ecx, [esp + 20]
+    xor edx, edx
+
+.next:
+    cmp edx, ecx
+    je .done
+.load:
+    mov al, [esi + edx]
+    mov [edi + edx], al
+    test al, al
+    jz .done
+    inc edx
+    jmp .next
+.done:
+    mov eax, edx
+    pop edi
+    pop esi
+    ret
+.fault:
+    mov eax, -1
+    pop edi
+    pop esi
+    ret
+
+EX_TABLE user_strncpy.load, user_strncpy.fault
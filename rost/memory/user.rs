@@ -0,0 +1,94 @@
+use core::prelude::*;
+use core::cmp::min;
+use core::mem::{size_of, uninitialized};
+
+use memory::{virt, KERNEL_SPACE};
+use exec::errno::{Errno, EFAULT};
+
+// One entry per instruction in user.asm that may fault on user memory
+#[packed]
+struct ExceptionEntry {
+    insn: u32,
+    fixup: u32
+}
+
+extern {
+    fn user_copy(dst: *mut u8, src: *const u8, len: u32) -> u32;
+    fn user_strncpy(dst: *mut u8, src: *const u8, max: u32) -> i32;
+
+    static __ex_table_start: ExceptionEntry;
+    static __ex_table_end: ExceptionEntry;
+}
+
+/// Copies len bytes from user space into the kernel.
+pub fn copy_from_user(dst: *mut u8, src: u32, len: uint) -> Result<(), Errno> {
+    if !virt::check_user_range(src, len as u32, false) {
+        return Err(EFAULT);
+    }
+
+    match unsafe { user_copy(dst, src as *const u8, len as u32) } {
+        0 => Ok(()),
+        _ => Err(EFAULT)
+    }
+}
+
+/// Copies len bytes from the kernel out to user space.
+pub fn copy_to_user(dst: u32, src: *const u8, len: uint) -> Result<(), Errno> {
+    if !virt::check_user_range(dst, len as u32, true) {
+        return Err(EFAULT);
+    }
+
+    match unsafe { user_copy(dst as *mut u8, src, len as u32) } {
+        0 => Ok(()),
+        _ => Err(EFAULT)
+    }
+}
+
+/// Copies a null terminated string from user space, returning its length
+/// without the terminator. A length of dst.len() means it didn't fit and
+/// dst holds no terminator.
+pub fn strncpy_from_user(dst: &mut [u8], src: u32) -> Result<uint, Errno> {
+    // Don't walk off the end of user space
+    let max = min(dst.len() as u32, KERNEL_SPACE - min(src, KERNEL_SPACE));
+    if !virt::check_user_range(src, max, false) {
+        return Err(EFAULT);
+    }
+
+    match unsafe { user_strncpy(dst.as_mut_ptr(), src as *const u8, max) } {
+        -1 => Err(EFAULT),
+        // The string runs into kernel space
+        len if len as u32 == max && max < dst.len() as u32 => Err(EFAULT),
+        len => Ok(len as uint)
+    }
+}
+
+/// Reads a value of type T from user space.
+pub fn read_user<T>(src: *const T) -> Result<T, Errno> {
+    unsafe {
+        let mut value: T = uninitialized();
+        try!(copy_from_user(&mut value as *mut T as *mut u8, src as u32, size_of::<T>()));
+        Ok(value)
+    }
+}
+
+/// Writes a value of type T to user space.
+pub fn write_user<T>(dst: *mut T, value: T) -> Result<(), Errno> {
+    copy_to_user(dst as u32, &value as *const T as *const u8, size_of::<T>())
+}
+
+/// Returns where to continue if the instruction at eip faulted while
+/// accessing user memory, called from the page fault handler.
+pub fn search_fixup(eip: u32) -> Option<u32> {
+    unsafe {
+        let mut entry = &__ex_table_start as *const ExceptionEntry;
+        let end = &__ex_table_end as *const ExceptionEntry;
+        while entry < end {
+            if (*entry).insn == eip {
+                return Some((*entry).fixup);
+            }
+            entry = entry.offset(1);
+        }
+    }
+
+    None
+}
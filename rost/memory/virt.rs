@@ -1,13 +1,67 @@
 use core::prelude::*;
-use core::mem::{transmute, size_of};
-use core::ptr::copy_nonoverlapping_memory;
+use core::mem::transmute;
+use core::ptr::{copy_nonoverlapping_memory, set_memory};
 
 use arch::idt;
-use memory::physical;
+#[cfg(pae)]
+use arch::cpu;
+use memory::{physical, user};
+use exec::{tasking, signal};
 
 static PAGE_SIZE: u32 = 0x1000;
 static PAGE_MASK: u32 = 0xFFFFF000;
+
+// With PAE entries are 64 bits wide, so a table holds half as many and
+// the directory is split over four pages pointed to by a PDPT. The four
+// pages are mapped next to each other, letting us treat them as one.
+#[cfg(not(pae))]
+type RawEntry = u32;
+#[cfg(pae)]
+type RawEntry = u64;
+
+#[cfg(not(pae))]
 static ENTRIES: u32 = 1024;
+#[cfg(pae)]
+static ENTRIES: u32 = 512;
+
+#[cfg(not(pae))]
+static DIRECTORY_PAGES: u32 = 1;
+#[cfg(pae)]
+static DIRECTORY_PAGES: u32 = 4;
+
+static DIRECTORY_ENTRIES: u32 = ENTRIES * DIRECTORY_PAGES;
+
+// The amount of memory covered by one table
+static TABLE_SPAN: u32 = PAGE_SIZE * ENTRIES;
+
+// Disables instruction fetches from a page, only available with PAE
+#[cfg(pae)]
+static NX: u64 = 1 << 63;
+#[cfg(pae)]
+static mut nx_enabled: bool = false;
+
+// Nothing is mapped below this, catching null pointer dereferences
+pub static USER_SPACE: u32 = 0x400000;
+// Everything from here and up is shared between all directories and only
+// accessible from kernel mode
+pub static KERNEL_SPACE: u32 = 0xC0000000;
+
+// The kernel is linked here, with the start of physical memory mapped
+// below it, see linker.ld
+pub static KERNEL_BASE: u32 = KERNEL_SPACE;
+// The amount of physical memory mapped at KERNEL_BASE
+static LOW_MEMORY: u32 = 0x400000;
+
+// User stacks start out small right below STACK_TOP and grow down on
+// demand, up to STACK_LIMIT. The page below that is never mapped so
+// overflowing the stack faults instead of running into other mappings.
+pub static STACK_TOP: u32 = KERNEL_SPACE - PAGE_SIZE;
+pub static STACK_LIMIT: u32 = 8 * 1024 * 1024;
+pub static STACK_GUARD: u32 = STACK_TOP - STACK_LIMIT - PAGE_SIZE;
+
+// How far below the user esp the stack may still grow, enough for pusha
+// and enter like on Linux. Anything further down is a stray access.
+static STACK_SLACK: u32 = 65536 + 32 * 4;
 
 bitflags!(
     #[packed]
@@ -18,59 +72,85 @@ bitflags!(
         static USER     = 1 << 2,
         #[allow(dead_code)]
         static ACCESSED = 1 << 5,
-        static EXEC     = 1 << 7
+        // Not stored in the entry, executable pages are those without NX
+        static EXEC     = 1 << 7,
+        // One of the bits left for the OS, marks pages shared by fork()
+        static COW      = 1 << 9
     }
 )
 
 #[packed]
-struct Page(u32);
+struct Page(RawEntry);
 
 #[packed]
-struct Table<U> {
+struct PageTable {
     entries: [Page, ..ENTRIES]
 }
 
-type PageTable = Table<Page>;
-type PageDirectory = Table<PageTable>;
+#[packed]
+struct PageDirectory {
+    entries: [Page, ..DIRECTORY_ENTRIES]
+}
 
+// The directory maps itself at the very end of the address space, making
+// the tables visible right below it. Another directory can be mapped the
+// same way right below that.
+#[cfg(not(pae))]
 static DIRECTORY: u32 = 0xFFFFF000;
+#[cfg(not(pae))]
 static DIRECTORY_SECONDARY: u32 = 0xFFBFF000;
-#[allow(dead_code)]
-static PAGES: u32 = 0xFFC00000;
+#[cfg(pae)]
+static DIRECTORY: u32 = 0xFFFFC000;
+#[cfg(pae)]
+static DIRECTORY_SECONDARY: u32 = 0xFF7FC000;
+
+static SECONDARY_TABLES: u32 = DIRECTORY_SECONDARY - (DIRECTORY_ENTRIES - DIRECTORY_PAGES) * PAGE_SIZE;
 
 // Temporary virtual addresses useful for mapping in physical pages
-static TEMP1: u32 = 0xFF7FF000;
-static TEMP2: u32 = 0xFF7FE000;
+static TEMP1: u32 = SECONDARY_TABLES - PAGE_SIZE;
+static TEMP2: u32 = SECONDARY_TABLES - 2 * PAGE_SIZE;
 
 static current_directory: *mut PageDirectory = DIRECTORY as *mut PageDirectory;
 
 pub static mut kernel_directory: u32 = 0;
 
+// Until the kernel directory is in use frames are reached through the
+// mapping boot.asm set up, which only covers low memory
+static mut boot_mapping: bool = true;
+
 pub fn init() {
     unsafe {
-        let directory = physical::allocate_frame() as *mut PageDirectory;
-        *directory = Table::empty();
+        enable_nx();
 
-        let table = physical::allocate_frame() as *mut PageTable;
-        *table = Table::empty();
+        let directory = new_directory();
+        let frames = directory_frames(directory);
 
-        // Identity map table the whole table, 4MB
+        // Map low memory at KERNEL_BASE, the kernel lives here. Nothing is
+        // left at its physical address and user mode can't touch any of it.
         let mut i = 0;
-        while i < PAGE_SIZE * ENTRIES {
-            (*table).set(i, i, PRESENT | WRITE | USER);
-            i += PAGE_SIZE;
-        }
+        while i < LOW_MEMORY {
+            let table_physical = physical::allocate_frame();
+            let table = map_temp(TEMP2, table_physical) as *mut PageTable;
+            *table = PageTable::empty();
+
+            for j in range(0, ENTRIES) {
+                let addr = i + j * PAGE_SIZE;
+                (*table).entries[j as uint] = Page::new(addr, PRESENT | WRITE | EXEC);
+            }
 
-        (*directory).set(0, table as u32, PRESENT | WRITE | USER);
+            let index = (KERNEL_BASE + i) / TABLE_SPAN;
+            let part = map_temp(TEMP1, frames[(index / ENTRIES) as uint]) as *mut PageTable;
+            (*part).entries[(index % ENTRIES) as uint] = Page::new(table_physical, PRESENT | WRITE | EXEC);
 
-        // Map the directory itself as the last entry
-        (*directory).set(DIRECTORY, directory as u32, PRESENT | WRITE);
+            i += TABLE_SPAN;
+        }
 
         idt::register_interrupt(14, page_fault);
 
-        kernel_directory = directory as u32;
+        kernel_directory = directory;
         switch_page_directory(kernel_directory);
-        enable_paging();
+        boot_mapping = false;
+        enable_write_protect();
     }
 }
 
@@ -78,11 +158,18 @@ pub fn map(addr: u32, size: u32, flags: Flags) {
     let f = translate_flags(flags);
 
     unsafe {
-        let mut current_addr = addr;
+        let mut current_addr = addr & PAGE_MASK;
         while current_addr < addr + size {
             let table = (*current_directory).fetch_table(current_addr, f);
 
+            if (*table).get(current_addr).present() {
+                // Segments may share a page, keep what's already there
+                current_addr += PAGE_SIZE;
+                continue;
+            }
+
             let phys_addr = physical::allocate_frame();
+            clear_page(phys_addr);
             (*table).set(current_addr, phys_addr, f);
             klog!("Mapping virtual {:x} to physical {:x}", current_addr, phys_addr);
 
@@ -91,15 +178,96 @@ pub fn map(addr: u32, size: u32, flags: Flags) {
     }
 }
 
-fn translate_flags(flags: Flags) -> Flags {
-    // TODO: Have external flags
-    let mut t = flags.clone();
+/// Maps a frame the kernel keeps hold of itself at addr, such as the vDSO.
+/// unmap() drops the reference taken here and leaves the frame alone.
+pub fn map_frame(addr: u32, frame: u32, flags: Flags) {
+    let f = translate_flags(flags);
 
-    if t.contains(EXEC) {
-        klog!("mmap(): EXEC is currently ignored");
-        t.remove(EXEC);
+    physical::share_frame(frame);
+    unsafe {
+        let table = (*current_directory).fetch_table(addr, f);
+        (*table).set(addr, frame, f);
     }
+}
+
+/// Changes the flags of the already mapped pages in the given range.
+pub fn protect(addr: u32, size: u32, flags: Flags) {
+    let f = translate_flags(flags);
 
+    unsafe {
+        let mut current_addr = addr & PAGE_MASK;
+        while current_addr < addr + size {
+            let page = (*current_directory).get_page(current_addr);
+            if page.present() {
+                let mut page_flags = f;
+                // Shared frames may only become writable through a copy
+                if f.contains(WRITE) && physical::is_shared(page.addr()) {
+                    page_flags.remove(WRITE);
+                    page_flags.insert(COW);
+                }
+                (*current_directory).set_page(current_addr, page.addr(), page_flags);
+            }
+
+            current_addr += PAGE_SIZE;
+        }
+    }
+}
+
+pub fn unmap(addr: u32, size: u32) {
+    unsafe {
+        let mut current_addr = addr;
+        while current_addr < addr + size {
+            let page = (*current_directory).get_page(current_addr);
+            if page.present() {
+                (*current_directory).set_page(current_addr, 0, NONE);
+                physical::free_frame(page.addr());
+                klog!("Unmapping virtual {:x} from physical {:x}", current_addr, page.addr());
+            }
+
+            current_addr += PAGE_SIZE;
+        }
+    }
+}
+
+/// Checks that the range is inside user space and that the pages already
+/// mapped in it are accessible from user mode. Pages that aren't mapped
+/// yet are left to the page fault handler.
+pub fn check_user_range(addr: u32, size: u32, write: bool) -> bool {
+    if size == 0 {
+        return true;
+    }
+
+    let end = addr as u64 + size as u64;
+    if addr < USER_SPACE || end > KERNEL_SPACE as u64 {
+        return false;
+    }
+
+    unsafe {
+        let mut current_addr = addr & PAGE_MASK;
+        while (current_addr as u64) < end {
+            let page = (*current_directory).get_page(current_addr);
+            if page.present() {
+                let flags = page.flags();
+                if !flags.contains(USER) {
+                    return false;
+                }
+                // Shared pages are copied on the first write
+                if write && !flags.contains(WRITE) && !flags.contains(COW) {
+                    return false;
+                }
+            }
+
+            current_addr += PAGE_SIZE;
+        }
+    }
+
+    true
+}
+
+fn translate_flags(flags: Flags) -> Flags {
+    // TODO: Have external flags
+    // Without PAE there's no way to take EXEC away, every page is executable
+    let mut t = flags.clone();
     t.insert(PRESENT);
     t
 }
@@ -109,53 +277,181 @@ pub fn clone_directory() -> u32 {
         let directory_physical = new_directory();
         let directory = map_secondary_directory(directory_physical);
 
-        // Link first 4MB
-        (*directory).set_at(0, (*current_directory).get(0));
+        // Share everything up to the kernel. Writable pages are made read-only
+        // in both directories and copied once either side writes to them.
+        let mut i = USER_SPACE;
+        while i < KERNEL_SPACE {
+            if !(*current_directory).get(i).present() {
+                i += TABLE_SPAN;
+                continue;
+            }
 
-        // Copy everything up to the kernel
-        let mut i = ENTRIES * PAGE_SIZE;
-        while i < 0xC0000000 {
             let src_page = (*current_directory).get_page(i);
             if src_page.present() {
-                let dst_page = physical::allocate_frame();
-                (*directory).set_page(i, dst_page, src_page.flags());
-                copy_page(src_page.addr(), dst_page);
+                let mut flags = src_page.flags();
+                if flags.contains(WRITE) {
+                    flags.remove(WRITE);
+                    flags.insert(COW);
+                    (*current_directory).set_page(i, src_page.addr(), flags);
+                }
+
+                physical::share_frame(src_page.addr());
+                (*directory).set_page(i, src_page.addr(), flags);
             }
 
             i += PAGE_SIZE;
         }
 
         // Link all kernel space
-        while i < DIRECTORY_SECONDARY {
+        while i < SECONDARY_TABLES {
             // FIXME: Force table initialization, this is quite dirty but lets us skip
             // kernel space synchronisation for now
             (*current_directory).fetch_table(i, PRESENT | WRITE);
 
             (*directory).set_at(i, (*current_directory).get(i));
-            i += ENTRIES * PAGE_SIZE;
+            i += TABLE_SPAN;
         }
 
         directory_physical
     }
 }
 
+/// Unmaps everything below kernel space in the current directory, returning
+/// the frames and page tables to the physical allocator.
+pub fn clear_user_space() {
+    unsafe {
+        release_user_space(current_directory);
+        // Only the directory entries were flushed, not the pages below them
+        flush_all();
+    }
+}
+
+/// Frees a directory created by clone_directory(), along with everything
+/// mapped in its user space. The directory must not be the current one.
+pub fn free_directory(directory_physical: u32) {
+    unsafe {
+        kassert!(directory_physical != read_cr3());
+
+        let directory = map_secondary_directory(directory_physical);
+        release_user_space(directory);
+
+        for i in range(0, DIRECTORY_PAGES) {
+            (*current_directory).set_at(SECONDARY_TABLES + i * TABLE_SPAN, Page::empty());
+        }
+
+        let frames = directory_frames(directory_physical);
+        for frame in frames.iter() {
+            physical::free_frame(*frame);
+        }
+        release_directory_root(directory_physical);
+    }
+}
+
+unsafe fn release_user_space(directory: *mut PageDirectory) {
+    let mut i = USER_SPACE;
+    while i < KERNEL_SPACE {
+        let entry = (*directory).get(i);
+        if entry.present() {
+            let table = (*directory).table_at(i / TABLE_SPAN);
+            for page in (*table).entries.iter() {
+                if page.present() {
+                    physical::free_frame(page.addr());
+                }
+            }
+
+            (*directory).set_at(i, Page::empty());
+            physical::free_frame(entry.addr());
+        }
+
+        i += TABLE_SPAN;
+    }
+}
+
 unsafe fn map_secondary_directory(directory_physical: u32) -> *mut PageDirectory {
-    (*current_directory).set(DIRECTORY_SECONDARY, directory_physical, PRESENT | WRITE);
+    let frames = directory_frames(directory_physical);
+    for i in range(0, DIRECTORY_PAGES) {
+        let addr = SECONDARY_TABLES + i * TABLE_SPAN;
+        (*current_directory).set(addr, frames[i as uint], PRESENT | WRITE);
+    }
+
+    // The tables of whatever directory was mapped here before may linger in the TLB
+    flush_all();
     DIRECTORY_SECONDARY as *mut PageDirectory
 }
 
+// Returns a directory with nothing but the recursive mapping of itself
 unsafe fn new_directory() -> u32 {
-    let directory_physical = physical::allocate_frame();
+    let mut frames = [0, ..DIRECTORY_PAGES as uint];
+    for frame in frames.mut_iter() {
+        *frame = physical::allocate_frame();
+    }
 
-    (*current_directory).set_page(TEMP1, directory_physical, PRESENT | WRITE);
+    for i in range(0, DIRECTORY_PAGES as uint) {
+        let part = map_temp(TEMP1, frames[i]) as *mut PageTable;
+        *part = PageTable::empty();
+    }
 
-    let directory = TEMP1 as *mut PageDirectory;
-    *directory = Table::empty();
+    // Map the last pages onto the directory itself
+    let last = map_temp(TEMP1, frames[DIRECTORY_PAGES as uint - 1]) as *mut PageTable;
+    for i in range(0, DIRECTORY_PAGES) {
+        let index = ENTRIES - DIRECTORY_PAGES + i;
+        (*last).entries[index as uint] = Page::new(frames[i as uint], PRESENT | WRITE);
+    }
 
-    // Map the last page onto the directory itself
-    (*directory).set(DIRECTORY, directory_physical, PRESENT | WRITE);
+    new_directory_root(frames)
+}
+
+#[cfg(not(pae))]
+unsafe fn new_directory_root(frames: [u32, ..DIRECTORY_PAGES]) -> u32 {
+    frames[0]
+}
+
+// The PDPT only has a present bit, the rest is up to the directories
+#[cfg(pae)]
+unsafe fn new_directory_root(frames: [u32, ..DIRECTORY_PAGES]) -> u32 {
+    let root = physical::allocate_frame();
+    let pdpt = map_temp(TEMP1, root) as *mut PageTable;
+    *pdpt = PageTable::empty();
+    for i in range(0, DIRECTORY_PAGES as uint) {
+        (*pdpt).entries[i] = Page(frames[i] as u64 | PRESENT.bits() as u64);
+    }
+    root
+}
 
-    directory_physical
+// The frames making up the directory, in order
+#[cfg(not(pae))]
+unsafe fn directory_frames(directory_physical: u32) -> [u32, ..DIRECTORY_PAGES] {
+    [directory_physical]
+}
+
+#[cfg(pae)]
+unsafe fn directory_frames(directory_physical: u32) -> [u32, ..DIRECTORY_PAGES] {
+    let pdpt = map_temp(TEMP1, directory_physical) as *const PageTable;
+    let mut frames = [0, ..DIRECTORY_PAGES as uint];
+    for i in range(0, DIRECTORY_PAGES as uint) {
+        frames[i] = (*pdpt).entries[i].addr();
+    }
+    frames
+}
+
+#[cfg(not(pae))]
+unsafe fn release_directory_root(_: u32) {}
+
+#[cfg(pae)]
+unsafe fn release_directory_root(directory_physical: u32) {
+    physical::free_frame(directory_physical);
+}
+
+// Makes a physical page accessible, through the given temporary address
+// once the kernel directory is in use
+unsafe fn map_temp(temp: u32, phys: u32) -> u32 {
+    if boot_mapping {
+        kassert!(phys < LOW_MEMORY);
+        return phys + KERNEL_BASE;
+    }
+
+    (*current_directory).set_page(temp, phys, PRESENT | WRITE);
+    temp
 }
 
 unsafe fn copy_page(src: u32, dst: u32) {
@@ -164,35 +460,164 @@ unsafe fn copy_page(src: u32, dst: u32) {
     copy_nonoverlapping_memory(TEMP2 as *mut u8, TEMP1 as *const u8, PAGE_SIZE as uint);
 }
 
+unsafe fn clear_page(dst: u32) {
+    (*current_directory).set_page(TEMP2, dst, PRESENT | WRITE);
+    set_memory(TEMP2 as *mut u8, 0, PAGE_SIZE as uint);
+}
+
+unsafe fn copy_on_write(address: u32) -> bool {
+    let page = (*current_directory).get_page(address);
+    if !page.present() || !page.flags().contains(COW) {
+        return false;
+    }
+
+    let mut flags = page.flags();
+    flags.remove(COW);
+    flags.insert(WRITE);
+
+    if physical::is_shared(page.addr()) {
+        let frame = physical::allocate_frame();
+        copy_page(page.addr(), frame);
+        (*current_directory).set_page(address, frame, flags);
+        physical::free_frame(page.addr());
+    } else {
+        // Everyone else has let go of the frame already, just take it over
+        (*current_directory).set_page(address, page.addr(), flags);
+    }
+
+    true
+}
+
 fn page_fault(regs: &mut idt::Registers) {
     let address = read_faulting_address();
     let flags = Flags::from_bits_truncate(regs.err_code);
 
-    let reserved = regs.err_code & 0x8 == 0;
-    panic!("page fault! ( {}{}{}{}) at 0x{:x}",
-        if flags.contains(PRESENT) { "present " } else { "non-present " },
-        if flags.contains(WRITE) { "write " } else { " read " },
-        if flags.contains(USER) { "user-mode " } else { "kernel-mode " },
-        if reserved { "reserved " } else { "" },
-        address);
+    if resolve_fault(address, flags) {
+        return;
+    }
+
+    // The kernel failed to access user memory on behalf of a process, let
+    // the code doing it report the failure
+    if !flags.contains(USER) && address < KERNEL_SPACE {
+        match user::search_fixup(regs.eip) {
+            Some(fixup) => {
+                regs.eip = fixup;
+                return;
+            },
+            None => {}
+        }
+    }
+
+    let present = if flags.contains(PRESENT) { "present " } else { "non-present " };
+    let access = if flags.contains(WRITE) { "write " } else { "read " };
+    let mode = if flags.contains(USER) { "user-mode " } else { "kernel-mode " };
+    let reserved = if regs.err_code & 0x8 != 0 { "reserved " } else { "" };
+    let fetch = if regs.err_code & 0x10 != 0 { "instruction-fetch " } else { "" };
+
+    if flags.contains(USER) {
+        // Only the process is at fault, take it down and leave the rest running
+        if is_stack_guard(address) {
+            kprintln!("Process {} killed: stack overflow at 0x{:x}, eip 0x{:x}",
+                tasking::get_current_task().pid, address, regs.eip);
+            tasking::kill(signal::SIGSEGV);
+        }
+
+        kprintln!("Process {} killed: page fault ( {}{}{}{}{}) at 0x{:x}, eip 0x{:x}",
+            tasking::get_current_task().pid, present, access, mode, reserved, fetch, address, regs.eip);
+        tasking::kill(signal::SIGSEGV);
+    }
+
+    panic!("page fault! ( {}{}{}{}{}) at 0x{:x}, eip 0x{:x}",
+        present, access, mode, reserved, fetch, address, regs.eip);
+}
+
+fn resolve_fault(address: u32, flags: Flags) -> bool {
+    // Kernel space is always mapped up front
+    if address >= KERNEL_SPACE {
+        return false;
+    }
+
+    if flags.contains(PRESENT) {
+        // Protection violation, only writes to shared pages can be resolved
+        flags.contains(WRITE) && unsafe { copy_on_write(address) }
+    } else {
+        demand_page(address)
+    }
+}
+
+// Fills in a non-present page on first access. The stack grows down to
+// the faulting page if that's close enough to the user esp, brk() and
+// mmap() regions are mapped a page at a time.
+fn demand_page(address: u32) -> bool {
+    let task = tasking::get_current_task();
+    if address < STACK_GUARD + PAGE_SIZE || address >= STACK_TOP {
+        return match task.mm.fault(address) {
+            Some(flags) => {
+                map(address & PAGE_MASK, PAGE_SIZE, flags);
+                true
+            },
+            None => false
+        };
+    }
+
+    if address + STACK_SLACK < task.user_regs().useresp {
+        return false;
+    }
+
+    unsafe {
+        // Find the current bottom of the stack and extend it with the same flags
+        let start = address & PAGE_MASK;
+        let mut bottom = start;
+        while bottom < STACK_TOP && !(*current_directory).get_page(bottom).present() {
+            bottom += PAGE_SIZE;
+        }
+
+        let mut flags = PRESENT | WRITE | USER;
+        if bottom < STACK_TOP {
+            flags = (*current_directory).get_page(bottom).flags();
+            // The new pages are private even if the old ones are shared
+            if flags.contains(COW) {
+                flags.remove(COW);
+                flags.insert(WRITE);
+            }
+        }
+
+        klog!("Growing stack from 0x{:x} to 0x{:x}", bottom, start);
+        map(start, bottom - start, flags);
+    }
+
+    true
+}
+
+fn is_stack_guard(address: u32) -> bool {
+    address >= STACK_GUARD && address < STACK_GUARD + PAGE_SIZE
 }
 
 impl Page {
     fn empty() -> Page { Page(0) }
 
     fn new(addr: u32, flags: Flags) -> Page {
-        Page(addr | flags.bits())
+        let mut bits = flags;
+        bits.remove(EXEC);
+        Page(addr as RawEntry | bits.bits() as RawEntry | no_exec_bit(flags))
     }
 
     fn addr(self) -> u32 {
         match self {
-            Page(addr) => addr & PAGE_MASK
+            Page(value) => value as u32 & PAGE_MASK
         }
     }
 
     fn flags(self) -> Flags {
         match self {
-            Page(value) => Flags::from_bits_truncate(value)
+            Page(value) => {
+                let mut flags = Flags::from_bits_truncate(value as u32 & !PAGE_MASK);
+                flags.remove(EXEC);
+                if is_executable(value) {
+                    flags.insert(EXEC);
+                }
+                flags
+            }
         }
     }
 
@@ -201,32 +626,55 @@ impl Page {
     }
 }
 
-impl<U> Table<U> {
-    fn empty() -> Table<U> {
-        Table { entries: [Page::empty(), ..ENTRIES as uint] }
+#[cfg(not(pae))]
+fn no_exec_bit(_: Flags) -> RawEntry { 0 }
+
+#[cfg(not(pae))]
+fn is_executable(_: RawEntry) -> bool { true }
+
+#[cfg(pae)]
+fn no_exec_bit(flags: Flags) -> RawEntry {
+    if unsafe { nx_enabled } && !flags.contains(EXEC) { NX } else { 0 }
+}
+
+#[cfg(pae)]
+fn is_executable(value: RawEntry) -> bool {
+    value & NX == 0
+}
+
+impl PageTable {
+    fn empty() -> PageTable {
+        PageTable { entries: [Page::empty(), ..ENTRIES as uint] }
     }
 
-    fn get(&mut self, addr: u32) -> Page {
-        let level = size_of::<U>() / size_of::<Page>();
-        let index = (addr / (PAGE_SIZE * level as u32)) % ENTRIES;
+    fn get(&self, addr: u32) -> Page {
+        let index = (addr / PAGE_SIZE) % ENTRIES;
         self.entries[index as uint]
     }
 
+    fn set(&mut self, addr: u32, phys: u32, flags: Flags) {
+        let index = (addr / PAGE_SIZE) % ENTRIES;
+        self.entries[index as uint] = Page::new(phys, flags);
+        flush_tlb(addr);
+    }
+}
+
+impl PageDirectory {
+    fn get(&self, addr: u32) -> Page {
+        self.entries[(addr / TABLE_SPAN) as uint]
+    }
+
     fn set(&mut self, addr: u32, phys: u32, flags: Flags) {
         self.set_at(addr, Page::new(phys, flags));
     }
 
     fn set_at(&mut self, addr: u32, page: Page) {
-        let level = size_of::<U>() / size_of::<Page>();
-        let index = (addr / (PAGE_SIZE * level as u32)) % ENTRIES;
-        self.entries[index as uint] = page;
+        self.entries[(addr / TABLE_SPAN) as uint] = page;
         flush_tlb(addr);
     }
-}
 
-impl Table<Table<Page>> {
     fn get_page(&self, addr: u32) -> Page {
-        let index = addr / (PAGE_SIZE * ENTRIES);
+        let index = addr / TABLE_SPAN;
         match self.entries[index as uint] {
             p if p.present() => unsafe {
                 let table = self.table_at(index);
@@ -244,20 +692,27 @@ impl Table<Table<Page>> {
     }
 
     fn fetch_table(&mut self, addr: u32, flags: Flags) -> *mut PageTable {
-        let index = addr / (PAGE_SIZE * ENTRIES);
+        let index = addr / TABLE_SPAN;
         match self.entries[index as uint] {
             p if p.present() => self.table_at(index),
             _ => unsafe {
                 // Allocate table
                 let table_physical = physical::allocate_frame();
-                
-                self.entries[index as uint] = Page::new(table_physical, flags);
+
+                // Access is controlled by the pages, don't let the first
+                // mapping in the table restrict the others
+                let mut table_flags = PRESENT | WRITE | EXEC;
+                if flags.contains(USER) {
+                    table_flags.insert(USER);
+                }
+
+                self.entries[index as uint] = Page::new(table_physical, table_flags);
 
                 let table = self.table_at(index);
                 // Flush table so we can write to its virtual address
                 flush_tlb(table);
 
-                *table = Table::empty();
+                *table = PageTable::empty();
                 table
             }
         }
@@ -265,9 +720,9 @@ impl Table<Table<Page>> {
 
     fn table_at(&self, index: u32) -> *mut PageTable {
         let self_addr: u32 = unsafe { transmute(self as *const PageDirectory) };
-        let size = size_of::<PageTable>() as u32;
-        let start = self_addr - (ENTRIES - 1) * size;
-        (start + index * size) as *mut PageTable
+        // The directory is the last few tables in its own window
+        let start = self_addr - (DIRECTORY_ENTRIES - DIRECTORY_PAGES) * PAGE_SIZE;
+        (start + index * PAGE_SIZE) as *mut PageTable
     }
 }
 
@@ -277,16 +732,50 @@ fn flush_tlb<T>(addr: T) {
     }
 }
 
+fn flush_all() {
+    unsafe {
+        write_cr3(read_cr3());
+    }
+}
+
 pub fn switch_page_directory(directory: u32) {
     unsafe {
         asm!("mov $0, %cr3" :: "r"(directory) :: "volatile");
     }
 }
 
-fn enable_paging() {
+// Paging itself is enabled by boot.asm, before jumping to the higher half
+fn enable_write_protect() {
+    unsafe {
+        // Makes writes to copy-on-write pages from kernel mode fault as well
+        write_cr0(read_cr0() | 0x10000);
+    }
+}
+
+#[cfg(not(pae))]
+fn enable_nx() {}
+
+// Not every CPU with PAE knows about NX, setting it on those would fault
+#[cfg(pae)]
+fn enable_nx() {
+    static EFER: u32 = 0xC0000080;
+    static EFER_NXE: u64 = 1 << 11;
+
+    let (max_extended, _, _, _) = cpu::cpuid(0x80000000);
+    if max_extended < 0x80000001 {
+        klog!("NX is not supported, all pages are executable");
+        return;
+    }
+
+    let (_, _, _, features) = cpu::cpuid(0x80000001);
+    if features & (1 << 20) == 0 {
+        klog!("NX is not supported, all pages are executable");
+        return;
+    }
+
     unsafe {
-        // Set the paging bit in CR0 to 1
-        write_cr0(read_cr0() | 0x80000000);
+        cpu::write_msr(EFER, cpu::read_msr(EFER) | EFER_NXE);
+        nx_enabled = true;
     }
 }
 
@@ -298,6 +787,16 @@ fn read_faulting_address() -> u32 {
     }
 }
 
+unsafe fn read_cr3() -> u32 {
+    let mut value;
+    asm!("mov %cr3, $0" : "=r"(value));
+    value
+}
+
+unsafe fn write_cr3(value: u32) {
+    asm!("mov $0, %cr3" :: "r"(value) : "memory" : "volatile");
+}
+
 unsafe fn read_cr0() -> u32 {
     let mut value;
     asm!("mov %cr0, $0" : "=r"(value));
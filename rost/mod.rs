@@ -33,16 +33,23 @@ mod std {
 }
 
 #[no_mangle]
-pub extern fn kernel_main() {
+pub extern fn kernel_main(magic: u32, info: *const arch::multiboot::Info) {
     arch::gdt::init();
     arch::irq::init();
     arch::idt::init();
     drivers::init();
 
-    memory::init();
+    if magic != arch::multiboot::BOOTLOADER_MAGIC {
+        panic!("Not loaded by a multiboot compliant bootloader");
+    }
+
+    // The bootloader passes a physical address, reach it through the kernel mapping
+    let info = (info as u32 + memory::KERNEL_BASE) as *const arch::multiboot::Info;
+    memory::init(unsafe { &*info });
     exec::tasking::init();
 
     exec::syscalls::init();
+    exec::vdso::init();
 
     kernel::console::init();
 
@@ -58,21 +65,20 @@ pub extern fn kernel_main() {
 
 fn idle() -> ! {
     loop {
+        // Orphans whose parents are gone end up with us
+        exec::tasking::reap_children();
         exec::tasking::schedule();
-    }   
+    }
 }
 
 fn do_stuff() -> ! {
-    extern { static _binary_test_fork_elf_start: u8; }
-    let do_nothing = &_binary_test_fork_elf_start as *const u8;
+    static INIT: &'static str = "/bin/test_fork";
 
-    if exec::elf::probe(do_nothing) {
-        kprintln!("Found program");
+    let mut args = exec::args::Arguments::new().ok().expect("Out of memory");
+    args.push_arg(INIT.as_ptr(), INIT.len()).ok().expect("Arguments too long");
 
-        exec::elf::exec(do_nothing);
-    }
-
-    unreachable!();
+    let error = exec::tasking::execve(INIT, args);
+    panic!("Failed to start {}, error {}", INIT, error as u32);
 }
 
 #[allow(visible_private_types)]
@@ -103,8 +109,23 @@ pub unsafe extern fn free(p: *mut c_void) {
 
 #[no_mangle]
 pub unsafe extern fn posix_memalign(memptr: *mut *mut c_void, align: size_t, size: size_t) -> c_int {
-    kprintln!("Allocating {} bytes with align {}", size, align);
-    *memptr = malloc(size);
+    use core::mem::size_of;
+    use memory::malloc::memalign;
+
+    static EINVAL: c_int = 22;
+    static ENOMEM: c_int = 12;
+
+    let align = align as uint;
+    if align == 0 || align & (align - 1) != 0 || align % size_of::<*mut c_void>() != 0 {
+        return EINVAL;
+    }
+
+    let p = memalign(align, size);
+    if p.is_null() {
+        return ENOMEM;
+    }
+
+    *memptr = p;
     0
 }
 
@@ -133,6 +133,70 @@ impl<T: Send> List<T> {
         }
     }
 
+    /// Inserts the value in front of the first element for which the
+    /// predicate returns true, or at the back if there is no such element.
+    pub fn insert_when(&mut self, value: T, f: |&T| -> bool) {
+        let mut link: *mut Link<T> = &mut self.head;
+        unsafe {
+            loop {
+                let found = match *link {
+                    None => return self.append(value),
+                    Some(ref node) => f(&node.value)
+                };
+
+                if found {
+                    break;
+                }
+
+                link = match *link {
+                    None => unreachable!(),
+                    Some(ref mut node) => &mut node.next
+                };
+            }
+
+            let next = mem::replace(&mut *link, None);
+            *link = Some(Unique::new(Node::new(value, next)));
+            self.length += 1;
+        }
+    }
+
+    /// Removes and returns the first element for which the predicate
+    /// returns true.
+    pub fn remove_where(&mut self, f: |&T| -> bool) -> Option<T> {
+        let mut prev: *mut Node<T> = 0 as *mut Node<T>;
+        let mut link: *mut Link<T> = &mut self.head;
+        unsafe {
+            loop {
+                let found = match *link {
+                    None => return None,
+                    Some(ref node) => f(&node.value)
+                };
+
+                if found {
+                    break;
+                }
+
+                link = match *link {
+                    None => unreachable!(),
+                    Some(ref mut node) => {
+                        prev = node.deref_mut() as *mut Node<T>;
+                        &mut node.next
+                    }
+                };
+            }
+
+            let mut node = (*link).take().unwrap();
+            *link = node.next.take();
+            if (*link).is_none() {
+                // The tail was removed
+                self.tail = Rawlink { p: prev };
+            }
+            self.length -= 1;
+
+            Some(node.take().value)
+        }
+    }
+
     pub fn pop_front(&mut self) -> Option<T> {
         self.pop_front_node().as_mut().map(|node| {
             node.take().value
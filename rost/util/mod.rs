@@ -4,4 +4,5 @@ pub use self::mem::Unique;
 
 mod bitflags;
 pub mod list;
+pub mod random;
 mod mem;
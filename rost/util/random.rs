@@ -0,0 +1,27 @@
+use core::prelude::*;
+
+// Not suitable for anything that needs to be secure, but good enough to
+// make addresses and the like hard to guess
+static mut state: u32 = 0;
+
+/// Returns the next number from a xorshift generator seeded by the cycle counter.
+pub fn next() -> u32 {
+    unsafe {
+        if state == 0 {
+            state = read_tsc() | 1;
+        }
+
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state
+    }
+}
+
+fn read_tsc() -> u32 {
+    unsafe {
+        let mut low: u32;
+        asm!("rdtsc" : "={eax}"(low) :: "edx" : "volatile");
+        low
+    }
+}
@@ -1,5 +1,11 @@
+; Function prologues call this when the stack would grow below the limit in
+; [gs:0x30]. The limit is cleared so reporting it doesn't end up back here.
 global __morestack
 __morestack:
+    mov dword [gs:0x30], 0
+
+    extern kernel_stack_overflow
+    call kernel_stack_overflow
     jmp $
 
 global __divdi3